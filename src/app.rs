@@ -2,36 +2,70 @@ use anyhow::{Context, Result};
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        Path as AxumPath, State, WebSocketUpgrade,
+        Path as AxumPath, Query, Request, State, WebSocketUpgrade,
     },
-    http::{header, HeaderMap, StatusCode},
-    response::{Html, IntoResponse},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Json, Redirect},
     routing::get,
     Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+    Engine,
+};
 use futures_util::{SinkExt, StreamExt};
+use ignore::{
+    gitignore::Gitignore,
+    overrides::{Override, OverrideBuilder},
+    Match, WalkBuilder,
+};
+use lru::LruCache;
 use minijinja::{context, value::Value, Environment};
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{Config, Event, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use pulldown_latex::{config::DisplayMode, push_mathml, Parser, RenderConfig, Storage};
 use serde::{Deserialize, Serialize};
 use std::{
     fs,
-    net::{Ipv4Addr, Ipv6Addr},
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
-    sync::{Arc, OnceLock},
-    time::SystemTime,
+    sync::{
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, SystemTime},
+};
+use syntect::{
+    html::{ClassStyle, ClassedHTMLGenerator},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
 };
 use tokio::{
     net::TcpListener,
-    sync::{broadcast, mpsc, Mutex},
+    sync::{broadcast, mpsc, watch},
+    time::Instant,
 };
-use tower_http::cors::CorsLayer;
+use tower::ServiceExt;
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    services::ServeFile,
+};
+use yaml_rust2::{Yaml, YamlLoader};
 
 const TEMPLATE_NAME: &str = "main.html";
 static TEMPLATE_ENV: OnceLock<Environment<'static>> = OnceLock::new();
 const MERMAID_JS: &str = include_str!("../static/js/mermaid.min.js");
 const MERMAID_ETAG: &str = concat!("\"", env!("CARGO_PKG_VERSION"), "\"");
+static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+
+type SharedMarkdownState = Arc<MarkdownState>;
 
-type SharedMarkdownState = Arc<Mutex<MarkdownState>>;
+fn syntax_set() -> &'static SyntaxSet {
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
 
 fn template_env() -> &'static Environment<'static> {
     TEMPLATE_ENV.get_or_init(|| {
@@ -46,6 +80,22 @@ fn template_env() -> &'static Environment<'static> {
 enum ClientMessage {
     Ping,
     RequestRefresh,
+    /// Switch the page between the plain render and one annotated with changes.
+    ShowChanges {
+        since: ChangesMode,
+    },
+}
+
+/// Which earlier render a page's changes are highlighted against.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ChangesMode {
+    #[default]
+    Off,
+    /// Changes made by the most recent save.
+    Save,
+    /// Changes made since mdserve started tracking the file.
+    Session,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -53,28 +103,225 @@ enum ClientMessage {
 enum ServerMessage {
     Reload,
     Pong,
+    /// A tracked markdown file was re-rendered. Only pages showing it reload.
+    FileChanged {
+        file: String,
+    },
+    /// An image under the base directory changed. Only pages embedding it reload.
+    ImageChanged {
+        path: String,
+    },
+    /// Freshly rendered document body, swapped into `#content` in place,
+    /// and its headings for the table of contents. `changed_block` indexes
+    /// the first top-level element that changed, so the browser can scroll
+    /// to it.
+    Update {
+        html: String,
+        headings: Vec<Heading>,
+        changed_block: Option<usize>,
+    },
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct SearchParams {
+    #[serde(default)]
+    q: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct WebSocketParams {
+    /// Tracked file key of the page that opened the socket.
+    file: Option<String>,
 }
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// Recursively collects the markdown files under `dir` that pass `filter`,
+/// sorted by path.
+pub(crate) fn scan_markdown_files(dir: &Path, filter: &FileFilter) -> Result<Vec<PathBuf>> {
+    let walker = WalkBuilder::new(dir)
+        .standard_filters(false)
+        .follow_links(true)
+        .git_ignore(filter.ignore_files)
+        .ignore(filter.ignore_files)
+        .parents(filter.ignore_files)
+        .require_git(false)
+        .overrides(FileFilter::globs(dir, &filter.exclude, "!")?)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build();
+    let includes = FileFilter::globs(dir, &filter.include, "")?;
 
-pub(crate) fn scan_markdown_files(dir: &Path) -> Result<Vec<PathBuf>> {
     let mut md_files = Vec::new();
-    scan_recursive(dir, &mut md_files)?;
+    for entry in walker {
+        let path = entry?.into_path();
+        if path.is_file() && is_markdown_file(&path) && is_included(&includes, &path) {
+            md_files.push(path);
+        }
+    }
     md_files.sort();
     Ok(md_files)
 }
 
-fn scan_recursive(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            scan_recursive(&path, files)?;
-        } else if path.is_file() && is_markdown_file(&path) {
-            files.push(path);
+/// Decides which markdown files directory mode tracks: `.gitignore` and
+/// `.ignore` files plus `--exclude`/`--include` globs, applied both when
+/// scanning and, through a `FileMatcher`, when watching. `.git` is always
+/// skipped.
+#[derive(Debug, Clone)]
+pub(crate) struct FileFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    /// Whether `.gitignore` and `.ignore` files are honoured.
+    ignore_files: bool,
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        FileFilter {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            ignore_files: true,
         }
     }
-    Ok(())
+}
+
+impl FileFilter {
+    /// Globs use gitignore syntax, relative to the base directory. With any
+    /// `include` glob, only markdown files matching one are tracked; includes
+    /// narrow the set and never bring back ignored files.
+    pub(crate) fn new(
+        include: Vec<String>,
+        exclude: Vec<String>,
+        ignore_files: bool,
+    ) -> Result<Self> {
+        let filter = FileFilter {
+            include,
+            exclude,
+            ignore_files,
+        };
+        // Surface bad globs up front rather than on the first scan
+        FileFilter::globs(Path::new(""), &filter.include, "")?;
+        FileFilter::globs(Path::new(""), &filter.exclude, "!")?;
+        Ok(filter)
+    }
+
+    /// Builds a matcher for `globs` on paths under `base_dir`. Overrides
+    /// whitelist by default, so exclusions are passed with a `!` prefix.
+    fn globs(base_dir: &Path, globs: &[String], prefix: &str) -> Result<Override> {
+        let mut builder = OverrideBuilder::new(base_dir);
+        for glob in globs {
+            builder
+                .add(&format!("{prefix}{glob}"))
+                .with_context(|| format!("invalid glob {glob:?}"))?;
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Compiles the filter for paths under `base_dir`.
+    pub(crate) fn matcher(&self, base_dir: &Path) -> Result<FileMatcher> {
+        Ok(FileMatcher {
+            base_dir: base_dir.to_path_buf(),
+            excludes: Self::globs(base_dir, &self.exclude, "!")?,
+            includes: Self::globs(base_dir, &self.include, "")?,
+            ignore_files: self.ignore_files,
+            parsed: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+/// A `FileFilter` compiled for one base directory and kept for the life of
+/// the watcher. Globs are built once; each ignore file is parsed when first
+/// needed and again after `forget_ignore_files`.
+pub(crate) struct FileMatcher {
+    base_dir: PathBuf,
+    excludes: Override,
+    includes: Override,
+    ignore_files: bool,
+    /// Parsed `.ignore`/`.gitignore` files by path, `None` where there is
+    /// no such file.
+    parsed: Mutex<HashMap<PathBuf, Option<Arc<Gitignore>>>>,
+}
+
+impl FileMatcher {
+    /// Whether a file under the base directory is excluded, deciding each
+    /// directory on the way down the same way `scan_markdown_files` would.
+    fn is_ignored(&self, path: &Path) -> bool {
+        self.is_excluded(path, false) || !is_included(&self.includes, path)
+    }
+
+    /// Whether a directory is excluded, so nothing under it is tracked or
+    /// watched. Include globs only narrow files, never directories.
+    fn is_ignored_dir(&self, path: &Path) -> bool {
+        self.is_excluded(path, true)
+    }
+
+    /// Whether `path` lies inside an ignored directory, which only a
+    /// recursive watch reports events from.
+    fn is_in_ignored_dir(&self, path: &Path) -> bool {
+        path.parent().is_some_and(|dir| self.is_ignored_dir(dir))
+    }
+
+    fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.base_dir) else {
+            return false;
+        };
+
+        let components: Vec<_> = relative.components().collect();
+        let mut entry = self.base_dir.clone();
+        for (index, component) in components.iter().enumerate() {
+            entry.push(component);
+            let is_dir = is_dir || index + 1 < components.len();
+            if component.as_os_str() == ".git" {
+                return true;
+            }
+            if self.excludes.matched(&entry, is_dir).is_ignore()
+                || (self.ignore_files && self.ignore_files_match(&entry, is_dir))
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Checks `path` against the `.ignore` and `.gitignore` files of every
+    /// directory above it. The nearest file with a matching rule decides, and
+    /// `.ignore` takes precedence over `.gitignore` in the same directory.
+    fn ignore_files_match(&self, path: &Path, is_dir: bool) -> bool {
+        for dir in path.ancestors().skip(1) {
+            for name in [".ignore", ".gitignore"] {
+                let Some(rules) = self.ignore_file(dir.join(name)) else {
+                    continue;
+                };
+                match rules.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        false
+    }
+
+    fn ignore_file(&self, file: PathBuf) -> Option<Arc<Gitignore>> {
+        lock(&self.parsed)
+            .entry(file)
+            .or_insert_with_key(|file| file.is_file().then(|| Arc::new(Gitignore::new(file).0)))
+            .clone()
+    }
+
+    /// Drops the parsed ignore files, for when one was created, edited or
+    /// deleted.
+    fn forget_ignore_files(&self) {
+        lock(&self.parsed).clear();
+    }
+}
+
+fn is_included(includes: &Override, path: &Path) -> bool {
+    includes.is_empty() || includes.matched(path, false).is_whitelist()
+}
+
+fn is_ignore_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == ".ignore" || name == ".gitignore")
 }
 
 fn is_markdown_file(path: &Path) -> bool {
@@ -84,30 +331,201 @@ fn is_markdown_file(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
+/// A rendered document and what was collected from it while rendering.
+struct RenderedMarkdown {
+    html: String,
+    frontmatter: Frontmatter,
+    headings: Vec<Heading>,
+    sections: Vec<Section>,
+}
+
+/// A file being served. Only what the sidebar and search need is kept here;
+/// the rendered page lives in `MarkdownState::renders`.
 struct TrackedFile {
     path: PathBuf,
+    /// Modification time of the file when it was last read.
+    last_modified: SystemTime,
+    /// Metadata from the document's frontmatter; empty when it has none.
+    frontmatter: Frontmatter,
+    /// Hash of the content when last read. It tells a save that kept the
+    /// modification time from no change at all, and recognises renamed files.
+    content_hash: u64,
+    /// The file as it was at startup; `None` for files added later.
+    session: Option<SessionBaseline>,
+    /// Plain text of each section, indexed for search; `None` until the file
+    /// is first rendered or searched.
+    sections: Option<Vec<Section>>,
+}
+
+impl TrackedFile {
+    /// Reads the frontmatter of the file at `path` without rendering it,
+    /// returning the content alongside.
+    fn read(path: PathBuf) -> Result<(Self, String)> {
+        let last_modified = fs::metadata(&path)?.modified()?;
+        let markdown = fs::read_to_string(&path)?;
+        let tracked = TrackedFile {
+            path,
+            last_modified,
+            frontmatter: Frontmatter::parse(&markdown).unwrap_or_default(),
+            content_hash: content_hash(&markdown),
+            session: None,
+            sections: None,
+        };
+        Ok((tracked, markdown))
+    }
+}
+
+/// Startup content of a tracked file, the baseline for changes since the
+/// session started.
+#[derive(Clone)]
+struct SessionBaseline {
+    hash: u64,
+    /// The content itself, kept while the tree's startup content fits in
+    /// `SESSION_MARKDOWN_BUDGET`.
+    markdown: Option<Arc<str>>,
+}
+
+/// Bytes of startup content kept for session baselines. Files past it only
+/// keep a hash, so a baseline is lost when such a file is edited before it is
+/// first rendered.
+const SESSION_MARKDOWN_BUDGET: usize = 32 * 1024 * 1024;
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A cached render of a tracked file, current while the file's
+/// modification time is still `last_modified`.
+#[derive(Clone)]
+struct FileRender {
     last_modified: SystemTime,
     html: String,
+    /// Headings in document order, for the table of contents.
+    headings: Vec<Heading>,
+    /// Index of the first top-level block that differs from the previous render.
+    changed_block: Option<usize>,
+    /// Render before the most recent change.
+    previous_html: String,
+    /// First render of a file tracked from startup; empty for files created
+    /// later.
+    session_html: String,
+}
+
+/// Paths that disappeared and appeared in one burst of watcher events.
+#[derive(Debug, Default)]
+struct Moves {
+    vanished: Vec<PathBuf>,
+    appeared: Vec<PathBuf>,
+}
+
+impl Moves {
+    /// Sorts the paths of `events` by whether they were renamed or removed
+    /// away, or renamed or created into place.
+    fn from_events(events: &[Event]) -> Self {
+        use notify::event::{ModifyKind, RenameMode};
+        use notify::EventKind;
+
+        let mut moves = Moves::default();
+        for event in events {
+            match event.kind {
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                    moves.vanished.push(event.paths[0].clone());
+                    moves.appeared.push(event.paths[1].clone());
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                    moves.vanished.extend(event.paths.iter().cloned());
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::To)) | EventKind::Create(_) => {
+                    moves.appeared.extend(event.paths.iter().cloned());
+                }
+                // macOS doesn't say which side of the rename a path is on
+                EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => {
+                    for path in &event.paths {
+                        if path.exists() {
+                            moves.appeared.push(path.clone());
+                        } else {
+                            moves.vanished.push(path.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        moves
+    }
+
+    /// Whether `from` may have become `to`: one was at, or under, a path
+    /// that vanished, and the other is at the same place under a path that
+    /// appeared.
+    fn pairs(&self, from: &Path, to: &Path) -> bool {
+        self.vanished.iter().any(|vanished| {
+            let Ok(rest) = from.strip_prefix(vanished) else {
+                return false;
+            };
+            self.appeared
+                .iter()
+                .any(|appeared| to.strip_prefix(appeared).is_ok_and(|other| other == rest))
+        })
+    }
 }
 
+/// Rendered pages kept in memory unless `--render-cache` says otherwise.
+pub(crate) const DEFAULT_RENDER_CACHE: NonZeroUsize = match NonZeroUsize::new(256) {
+    Some(size) => size,
+    None => unreachable!(),
+};
+
+/// State shared by requests, sockets and the watcher task.
+///
+/// The locks only guard map lookups and updates: files are read and
+/// rendered with no lock held, and renders are handed out as `Arc`s, so a
+/// render in progress never holds up a request for another page. When more
+/// than one lock is needed they are taken in field order.
 struct MarkdownState {
     base_dir: PathBuf,
-    tracked_files: HashMap<String, TrackedFile>,
+    tracked_files: RwLock<HashMap<String, TrackedFile>>,
+    /// Renders of recently viewed files, by key. Files are rendered on first
+    /// use, and the least recently used render is dropped when full.
+    renders: Mutex<LruCache<String, Arc<FileRender>>>,
+    /// Old keys of renamed files, mapped to their current key.
+    redirects: RwLock<HashMap<String, String>>,
+    /// Moves seen since removals were last reconciled, one entry per burst.
+    moves: Mutex<Vec<Moves>>,
     is_directory_mode: bool,
     change_tx: broadcast::Sender<ServerMessage>,
 }
 
+/// Read-locks `lock`. Every update leaves the state's maps consistent, so a
+/// panic while one was locked doesn't make them unusable.
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 impl MarkdownState {
-    fn new(base_dir: PathBuf, file_paths: Vec<PathBuf>, is_directory_mode: bool) -> Result<Self> {
+    /// Tracks `file_paths` without rendering them; only their frontmatter is
+    /// read, so the sidebar is complete straight away. Up to `render_cache`
+    /// renders are kept.
+    fn new(
+        base_dir: PathBuf,
+        file_paths: Vec<PathBuf>,
+        is_directory_mode: bool,
+        render_cache: NonZeroUsize,
+    ) -> Result<Self> {
         let (change_tx, _) = broadcast::channel::<ServerMessage>(16);
 
         let mut tracked_files = HashMap::new();
+        let mut budget = SESSION_MARKDOWN_BUDGET;
         for file_path in file_paths {
-            let metadata = fs::metadata(&file_path)?;
-            let last_modified = metadata.modified()?;
-            let content = fs::read_to_string(&file_path)?;
-            let html = Self::markdown_to_html(&content)?;
-
             let canonical = file_path.canonicalize().unwrap_or(file_path);
             let key = canonical
                 .strip_prefix(&base_dir)
@@ -115,19 +533,24 @@ impl MarkdownState {
                 .to_string_lossy()
                 .to_string();
 
-            tracked_files.insert(
-                key,
-                TrackedFile {
-                    path: canonical,
-                    last_modified,
-                    html,
-                },
-            );
+            let (mut tracked, markdown) = TrackedFile::read(canonical)?;
+            let markdown = (markdown.len() <= budget).then(|| {
+                budget -= markdown.len();
+                Arc::from(markdown)
+            });
+            tracked.session = Some(SessionBaseline {
+                hash: tracked.content_hash,
+                markdown,
+            });
+            tracked_files.insert(key, tracked);
         }
 
         Ok(MarkdownState {
             base_dir,
-            tracked_files,
+            tracked_files: RwLock::new(tracked_files),
+            renders: Mutex::new(LruCache::new(render_cache)),
+            redirects: RwLock::new(HashMap::new()),
+            moves: Mutex::new(Vec::new()),
             is_directory_mode,
             change_tx,
         })
@@ -138,201 +561,2428 @@ impl MarkdownState {
     }
 
     fn get_sorted_filenames(&self) -> Vec<String> {
-        let mut filenames: Vec<_> = self.tracked_files.keys().cloned().collect();
+        let mut filenames: Vec<_> = read_lock(&self.tracked_files).keys().cloned().collect();
         filenames.sort();
         filenames
     }
 
-    fn refresh_file(&mut self, filename: &str) -> Result<()> {
-        if let Some(tracked) = self.tracked_files.get_mut(filename) {
-            let metadata = fs::metadata(&tracked.path)?;
-            let current_modified = metadata.modified()?;
+    /// Makes sure the cached render of `filename` is current, rendering the
+    /// file if it isn't cached or has been modified since. A re-render is
+    /// compared against the cached one to track what changed.
+    ///
+    /// A file that can't be read, such as one an editor is saving by
+    /// renaming and recreating it, keeps its render, or is rendered from its
+    /// startup content if it hasn't changed since, until the grace period
+    /// drops it.
+    fn refresh_file(&self, filename: &str) -> Result<()> {
+        self.refresh(filename, false)
+    }
+
+    /// Like `refresh_file`, for a file the watcher saw change: its content is
+    /// read even when the modification time hasn't moved, since coarse
+    /// timestamps don't tick for every save.
+    fn reload_file(&self, filename: &str) -> Result<()> {
+        self.refresh(filename, true)
+    }
+
+    fn refresh(&self, filename: &str, reread: bool) -> Result<()> {
+        let Some((path, known_hash, last_read)) =
+            read_lock(&self.tracked_files).get(filename).map(|tracked| {
+                let last_read = tracked
+                    .session
+                    .as_ref()
+                    .filter(|session| session.hash == tracked.content_hash)
+                    .and_then(|session| session.markdown.clone())
+                    .map(|markdown| (markdown.to_string(), tracked.last_modified));
+                (tracked.path.clone(), tracked.content_hash, last_read)
+            })
+        else {
+            return Ok(());
+        };
+        let cached = lock(&self.renders).get(filename).cloned();
+        let is_current = |modified: &SystemTime| {
+            cached
+                .as_ref()
+                .is_some_and(|cached| cached.last_modified >= *modified)
+        };
+
+        let read = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .and_then(|modified| {
+                if !reread && is_current(&modified) {
+                    return Ok(None);
+                }
+                Ok(Some((fs::read_to_string(&path)?, modified)))
+            });
+        let (content, current_modified) = match read {
+            Ok(Some(read)) => read,
+            Ok(None) => return Ok(()),
+            Err(_) if cached.is_some() => return Ok(()),
+            Err(e) => last_read.ok_or(e)?,
+        };
+        let hash = content_hash(&content);
+        if hash == known_hash && is_current(&current_modified) {
+            return Ok(());
+        }
+        let RenderedMarkdown {
+            html,
+            frontmatter,
+            headings,
+            sections,
+        } = Self::markdown_to_html(&content)?;
+
+        let render = match cached {
+            Some(cached) => FileRender {
+                last_modified: current_modified,
+                changed_block: first_changed_block(&cached.html, &html),
+                previous_html: cached.html.clone(),
+                session_html: cached.session_html.clone(),
+                html,
+                headings,
+            },
+            None => FileRender {
+                last_modified: current_modified,
+                changed_block: None,
+                previous_html: html.clone(),
+                session_html: self.session_html(filename, hash, &html)?,
+                html,
+                headings,
+            },
+        };
+
+        let mut tracked_files = write_lock(&self.tracked_files);
+        // The file may have been dropped while it was rendering
+        let Some(tracked) = tracked_files.get_mut(filename) else {
+            return Ok(());
+        };
+        if tracked.last_modified <= current_modified {
+            tracked.last_modified = current_modified;
+            tracked.frontmatter = frontmatter;
+            tracked.content_hash = hash;
+            tracked.sections = Some(sections);
+        }
+        // A concurrent refresh may have cached a newer version
+        let mut renders = lock(&self.renders);
+        if renders
+            .peek(filename)
+            .is_none_or(|cached| cached.last_modified <= current_modified)
+        {
+            renders.put(filename.to_string(), Arc::new(render));
+        }
+
+        Ok(())
+    }
+
+    /// Re-reads the frontmatter of a file that changed while it has no
+    /// cached render, keeping the sidebar current without rendering it.
+    fn refresh_frontmatter(&self, filename: &str) -> Result<()> {
+        let Some(path) = read_lock(&self.tracked_files)
+            .get(filename)
+            .map(|tracked| tracked.path.clone())
+        else {
+            return Ok(());
+        };
+        let (read, _) = TrackedFile::read(path)?;
 
-            if current_modified > tracked.last_modified {
-                let content = fs::read_to_string(&tracked.path)?;
-                tracked.html = Self::markdown_to_html(&content)?;
-                tracked.last_modified = current_modified;
+        if let Some(tracked) = write_lock(&self.tracked_files).get_mut(filename) {
+            if tracked.last_modified <= read.last_modified {
+                if tracked.content_hash != read.content_hash {
+                    tracked.sections = None;
+                }
+                tracked.last_modified = read.last_modified;
+                tracked.frontmatter = read.frontmatter;
+                tracked.content_hash = read.content_hash;
             }
         }
 
         Ok(())
     }
 
-    fn add_tracked_file(&mut self, file_path: PathBuf) -> Result<()> {
+    /// Renders the content `filename` had at startup, given the `hash` and
+    /// `html` of its current content; empty for files added later.
+    fn session_html(&self, filename: &str, hash: u64, html: &str) -> Result<String> {
+        let session = read_lock(&self.tracked_files)
+            .get(filename)
+            .and_then(|tracked| tracked.session.clone());
+        match session {
+            None => Ok(String::new()),
+            Some(session) if session.hash == hash => Ok(html.to_string()),
+            Some(SessionBaseline {
+                markdown: Some(markdown),
+                ..
+            }) => Ok(Self::markdown_to_html(&markdown)?.html),
+            // Edited before its first render, past the budget: changes count
+            // from here
+            Some(_) => Ok(html.to_string()),
+        }
+    }
+
+    /// The cached render of `key`, without counting as a use of it.
+    fn cached_render(&self, key: &str) -> Option<Arc<FileRender>> {
+        lock(&self.renders).peek(key).cloned()
+    }
+
+    fn add_tracked_file(&self, file_path: PathBuf) -> Result<()> {
         let key = file_path
             .strip_prefix(&self.base_dir)
             .unwrap_or(&file_path)
             .to_string_lossy()
             .to_string();
 
-        if self.tracked_files.contains_key(&key) {
+        if read_lock(&self.tracked_files).contains_key(&key) {
             return Ok(());
         }
+        let (tracked, _) = TrackedFile::read(file_path)?;
 
-        let metadata = fs::metadata(&file_path)?;
-        let content = fs::read_to_string(&file_path)?;
-
-        self.tracked_files.insert(
-            key,
-            TrackedFile {
-                path: file_path,
-                last_modified: metadata.modified()?,
-                html: Self::markdown_to_html(&content)?,
-            },
-        );
+        let mut tracked_files = write_lock(&self.tracked_files);
+        write_lock(&self.redirects).remove(&key);
+        tracked_files.entry(key).or_insert(tracked);
 
         Ok(())
     }
 
-    fn markdown_to_html(content: &str) -> Result<String> {
+    /// Remembers a burst's moves for the next `remove_missing_files`, when
+    /// it both removed and added something.
+    fn record_moves(&self, moves: Moves) {
+        if self.is_directory_mode && !moves.vanished.is_empty() && !moves.appeared.is_empty() {
+            lock(&self.moves).push(moves);
+        }
+    }
+
+    /// Drops tracked files whose path no longer exists. A dropped file counts
+    /// as renamed when a key added this session had its exact content and
+    /// appeared in the same burst as it vanished, as recorded by
+    /// `record_moves`: its old key redirects to the new one, which inherits
+    /// its change history. Returns whether anything was dropped.
+    fn remove_missing_files(&self) -> bool {
+        let moves = std::mem::take(&mut *lock(&self.moves));
+        let paths: Vec<(String, PathBuf)> = read_lock(&self.tracked_files)
+            .iter()
+            .map(|(key, tracked)| (key.clone(), tracked.path.clone()))
+            .collect();
+        let mut missing: Vec<String> = paths
+            .into_iter()
+            .filter(|(_, path)| !path.exists())
+            .map(|(key, _)| key)
+            .collect();
+        missing.sort();
+
+        let mut tracked_files = write_lock(&self.tracked_files);
+        let mut renders = lock(&self.renders);
+        let mut redirects = write_lock(&self.redirects);
+        let mut removed_any = false;
+        for key in &missing {
+            // Recreated since it was found missing
+            if tracked_files
+                .get(key)
+                .is_some_and(|tracked| tracked.path.exists())
+            {
+                continue;
+            }
+            let Some(removed) = tracked_files.remove(key) else {
+                continue;
+            };
+            removed_any = true;
+            let removed_render = renders.pop(key);
+
+            let mut candidates: Vec<&String> = tracked_files.keys().collect();
+            candidates.sort();
+            let renamed_to = candidates
+                .into_iter()
+                .find(|candidate| {
+                    let tracked = &tracked_files[*candidate];
+                    tracked.session.is_none()
+                        && tracked.content_hash == removed.content_hash
+                        && !redirects.values().any(|target| target == *candidate)
+                        && moves
+                            .iter()
+                            .any(|moves| moves.pairs(&removed.path, &tracked.path))
+                })
+                .cloned();
+            let Some(new_key) = renamed_to else {
+                continue;
+            };
+
+            if let Some(renamed) = tracked_files.get_mut(&new_key) {
+                renamed.session = removed.session;
+                if let Some(removed_render) = removed_render {
+                    let mut render = match renders.peek(&new_key) {
+                        Some(current) => FileRender::clone(current),
+                        // The content is unchanged, so the render stays current
+                        None => FileRender {
+                            last_modified: renamed.last_modified,
+                            ..FileRender::clone(&removed_render)
+                        },
+                    };
+                    render
+                        .previous_html
+                        .clone_from(&removed_render.previous_html);
+                    render.session_html.clone_from(&removed_render.session_html);
+                    renders.put(new_key.clone(), Arc::new(render));
+                } else {
+                    // Rendered as a new file, without the inherited baseline
+                    renders.pop(&new_key);
+                }
+            }
+            for target in redirects.values_mut() {
+                if target == key {
+                    target.clone_from(&new_key);
+                }
+            }
+            redirects.insert(key.clone(), new_key);
+        }
+
+        removed_any
+    }
+
+    /// Translates a broadcast change into the message for the socket of the
+    /// page showing `page`, or `None` when the change doesn't touch that page.
+    ///
+    /// Document edits are pushed as an in-place `Update`; anything that may
+    /// change the surrounding template or navigation falls back to `Reload`.
+    fn message_for_page(
+        &self,
+        change: &ServerMessage,
+        page: &str,
+        mode: ChangesMode,
+    ) -> Option<ServerMessage> {
+        match change {
+            ServerMessage::FileChanged { file } if file == page => {
+                // Annotations add elements for deleted blocks, so the plain
+                // block index no longer lines up; the client then scrolls to
+                // the first marked block instead
+                let changed_block = match mode {
+                    ChangesMode::Off => self
+                        .cached_render(page)
+                        .and_then(|render| render.changed_block),
+                    ChangesMode::Save | ChangesMode::Session => None,
+                };
+                // A reload shows whatever can still be served
+                Some(
+                    self.page_update(page, mode, changed_block)
+                        .unwrap_or(ServerMessage::Reload),
+                )
+            }
+
+            ServerMessage::FileChanged { .. } => None,
+            // Without a render to check, the page may show the image
+            ServerMessage::ImageChanged { path } => self
+                .cached_render(page)
+                .is_none_or(|render| referenced_images(page, &render.html).contains(path))
+                .then_some(ServerMessage::Reload),
+            ServerMessage::Reload | ServerMessage::Pong | ServerMessage::Update { .. } => {
+                Some(ServerMessage::Reload)
+            }
+        }
+    }
+
+    /// Builds the `Update` carrying the page's current content and outline.
+    fn page_update(
+        &self,
+        page: &str,
+        mode: ChangesMode,
+        changed_block: Option<usize>,
+    ) -> Option<ServerMessage> {
+        self.refresh_file(page).ok()?;
+        let render = self.cached_render(page)?;
+        Some(ServerMessage::Update {
+            html: self.page_html(page, &render, mode),
+            headings: render.headings.clone(),
+            changed_block,
+        })
+    }
+
+    /// Returns the page's rendered content, annotated with changes unless
+    /// `mode` is off.
+    fn page_html(&self, page: &str, render: &FileRender, mode: ChangesMode) -> String {
+        let html = match mode {
+            ChangesMode::Off => render.html.clone(),
+            ChangesMode::Save => annotate_changes(&render.previous_html, &render.html),
+            ChangesMode::Session => annotate_changes(&render.session_html, &render.html),
+        };
+        self.resolve_links(page, &html)
+    }
+
+    fn markdown_options() -> markdown::Options {
         let mut options = markdown::Options::gfm();
         options.compile.allow_dangerous_html = true;
         options.parse.constructs.frontmatter = true;
+        options.parse.constructs.math_flow = true;
+        options.parse.constructs.math_text = true;
+        options
+    }
+
+    /// Renders a document to HTML, returning it with the frontmatter,
+    /// headings and search sections it contains.
+    fn markdown_to_html(content: &str) -> Result<RenderedMarkdown> {
+        let frontmatter = Frontmatter::parse(content).unwrap_or_default();
+
+        let (html_body, headings) = Self::markdown_body(content);
+        let sections = split_sections(&html_body, &headings);
+
+        Ok(RenderedMarkdown {
+            html: frontmatter.to_html() + &highlight_code_blocks(&render_math(&html_body)),
+            frontmatter,
+            headings,
+            sections,
+        })
+    }
+
+    /// Converts `content` to HTML with heading ids, but without the code
+    /// highlighting, math and frontmatter panel of a full render. Enough to
+    /// index a file or look up its anchors.
+    fn markdown_body(content: &str) -> (String, Vec<Heading>) {
+        let html_body = markdown::to_html_with_options(content, &Self::markdown_options())
+            .unwrap_or_else(|_| "Error parsing markdown".to_string());
+        add_heading_ids(&html_body)
+    }
+
+    /// Finds tracked files containing every term of `query`, best matches
+    /// first. Each hit points at the section where the terms are densest.
+    ///
+    /// Files that haven't been rendered yet are indexed first.
+    fn search(&self, query: &str) -> Vec<SearchHit> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let unindexed: Vec<(String, PathBuf)> = read_lock(&self.tracked_files)
+            .iter()
+            .filter(|(_, tracked)| tracked.sections.is_none())
+            .map(|(key, tracked)| (key.clone(), tracked.path.clone()))
+            .collect();
+        for (key, path) in unindexed {
+            let Ok(content) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let (html, headings) = Self::markdown_body(&content);
+            let sections = split_sections(&html, &headings);
+            // A render in the meantime indexed a version at least as new
+            if let Some(tracked) = write_lock(&self.tracked_files).get_mut(&key) {
+                tracked.sections.get_or_insert(sections);
+            }
+        }
+
+        let mut hits: Vec<SearchHit> = read_lock(&self.tracked_files)
+            .iter()
+            .filter_map(|(key, tracked)| search_file(key, tracked, &terms))
+            .collect();
+        hits.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.file.cmp(&b.file)));
+        hits.truncate(MAX_SEARCH_HITS);
+        hits
+    }
+
+    /// Checks the links and images of every tracked file, in file order.
+    /// Files that can no longer be read are skipped.
+    fn check(&self) -> Vec<CheckIssue> {
+        let options = Self::markdown_options();
+
+        let mut paths: Vec<(String, PathBuf)> = read_lock(&self.tracked_files)
+            .iter()
+            .map(|(key, tracked)| (key.clone(), tracked.path.clone()))
+            .collect();
+        paths.sort();
+        let documents: Vec<(String, String)> = paths
+            .into_iter()
+            .filter_map(|(key, path)| Some((key, fs::read_to_string(path).ok()?)))
+            .collect();
+        let anchors: HashMap<&str, HashSet<String>> = documents
+            .iter()
+            .map(|(key, content)| {
+                let (html, _) = Self::markdown_body(content);
+                let ids = element_ids(&html).map(unescape_html).collect();
+                (key.as_str(), ids)
+            })
+            .collect();
+
+        let mut issues = Vec::new();
+        for (key, content) in &documents {
+            let Ok(tree) = markdown::to_mdast(content, &options.parse) else {
+                continue;
+            };
+
+            let mut references = Vec::new();
+            collect_references(&tree, &mut references);
+            issues.extend(
+                references
+                    .into_iter()
+                    .filter_map(|reference| self.check_reference(key, reference, &anchors)),
+            );
+        }
+
+        issues
+    }
+
+    /// Checks one link or image on `page` the way the server would resolve
+    /// it. `anchors` holds the element ids of each readable tracked file.
+    fn check_reference(
+        &self,
+        page: &str,
+        reference: Reference,
+        anchors: &HashMap<&str, HashSet<String>>,
+    ) -> Option<CheckIssue> {
+        let url = reference.url.as_str();
+        // Protocol-relative and scheme URLs (`https:`, `mailto:`) aren't ours
+        if url.starts_with("//") || url.contains(':') {
+            return None;
+        }
+
+        let (path, fragment) = url.split_at(url.find(['#', '?']).unwrap_or(url.len()));
+        let fragment = fragment.strip_prefix('#').unwrap_or("");
+        let path = percent_decode_str(path).decode_utf8_lossy();
+        let issue = |kind, message| CheckIssue {
+            file: page.to_string(),
+            line: reference.line,
+            column: reference.column,
+            kind,
+            target: reference.url.clone(),
+            message,
+        };
+
+        if path.is_empty() || is_markdown_file(Path::new(path.as_ref())) {
+            let key = if path.is_empty() {
+                page.to_string()
+            } else {
+                resolve_page_path(page, &path)
+            };
+            if !read_lock(&self.tracked_files).contains_key(&key) {
+                return Some(issue(
+                    CheckIssueKind::BrokenLink,
+                    format!("link to {key} is not a tracked file"),
+                ));
+            }
+            let fragment = percent_decode_str(fragment).decode_utf8_lossy();
+            let has_anchor = fragment.is_empty()
+                || anchors
+                    .get(key.as_str())
+                    .is_some_and(|ids| ids.contains(fragment.as_ref()));
+            return (!has_anchor).then(|| {
+                issue(
+                    CheckIssueKind::MissingAnchor,
+                    format!("{key} has no heading with id \"{fragment}\""),
+                )
+            });
+        }
+
+        let file = resolve_page_path(page, &path);
+        if file.is_empty() {
+            return None;
+        }
+        let kind = if reference.is_image || is_image_file(&file) {
+            CheckIssueKind::MissingImage
+        } else {
+            CheckIssueKind::BrokenLink
+        };
+        let message = match base_dir_path(&self.base_dir, &file) {
+            Err(StatusCode::FORBIDDEN) => format!("{file} is outside the served directory"),
+            Err(_) => format!("{file} does not exist"),
+            Ok(_) if static_content_type(&file).is_none() => format!("{file} is not served"),
+            Ok(_) => return None,
+        };
+        Some(issue(kind, message))
+    }
+
+    /// Resolves the markdown links in `html`, rendered from `page`, against
+    /// the tracked files.
+    fn resolve_links(&self, page: &str, html: &str) -> String {
+        let tracked_files = read_lock(&self.tracked_files);
+        resolve_markdown_links(page, html, |key| tracked_files.contains_key(key))
+    }
+
+    /// Frontmatter titles of tracked files, keyed like `tracked_files`.
+    fn titles(&self) -> HashMap<String, String> {
+        read_lock(&self.tracked_files)
+            .iter()
+            .filter_map(|(key, tracked)| Some((key.clone(), tracked.frontmatter.title.clone()?)))
+            .collect()
+    }
+}
+
+/// Document metadata from a leading YAML (`---`) or TOML (`+++`) block.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+struct Frontmatter {
+    title: Option<String>,
+    author: Option<String>,
+    status: Option<String>,
+    date: Option<String>,
+    tags: Vec<String>,
+}
+
+impl Frontmatter {
+    /// Parses the frontmatter block at the top of `content`. Returns `None`
+    /// when there is no block or it isn't a table of fields.
+    fn parse(content: &str) -> Option<Self> {
+        let lines: Vec<&str> = content.lines().collect();
+        let fence = lines.first()?.trim_end();
+        if fence != "---" && fence != "+++" {
+            return None;
+        }
+        let end = 1 + lines[1..]
+            .iter()
+            .position(|line| line.trim_end() == fence)?;
+        let block = lines[1..end].join("\n");
+
+        if fence == "---" {
+            let doc = YamlLoader::load_from_str(&block).ok()?.into_iter().next()?;
+            doc.as_hash()?;
+            Some(Self::from_fields(|key| yaml_strings(&doc[key])))
+        } else {
+            let table = block.parse::<toml::Table>().ok()?;
+            Some(Self::from_fields(|key| {
+                table.get(key).map(toml_strings).unwrap_or_default()
+            }))
+        }
+    }
+
+    /// Builds the metadata from a lookup returning each field's values.
+    /// Lists are joined (authors) or kept (tags); a single tag string is
+    /// split on commas.
+    fn from_fields(field: impl Fn(&str) -> Vec<String>) -> Self {
+        let joined = |key: &str| Some(field(key).join(", ")).filter(|value| !value.is_empty());
+
+        let mut tags = field("tags");
+        if let [single] = tags.as_slice() {
+            tags = single
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect();
+        }
+
+        Frontmatter {
+            title: joined("title"),
+            author: joined("author").or_else(|| joined("authors")),
+            status: joined("status"),
+            date: joined("date"),
+            tags,
+        }
+    }
+
+    /// Renders the collapsible panel shown above the document, or nothing
+    /// when none of the displayed fields are set.
+    fn to_html(&self) -> String {
+        let mut rows = String::new();
+        for (label, value) in [
+            ("Author", &self.author),
+            ("Status", &self.status),
+            ("Date", &self.date),
+        ] {
+            if let Some(value) = value {
+                rows.push_str(&format!(
+                    "<dt>{label}</dt><dd>{}</dd>\n",
+                    escape_html(value)
+                ));
+            }
+        }
+        if !self.tags.is_empty() {
+            let tags: Vec<String> = self
+                .tags
+                .iter()
+                .map(|tag| {
+                    format!(
+                        "<span class=\"frontmatter-tag\">{}</span>",
+                        escape_html(tag)
+                    )
+                })
+                .collect();
+            rows.push_str(&format!("<dt>Tags</dt><dd>{}</dd>\n", tags.join(" ")));
+        }
+
+        if rows.is_empty() && self.title.is_none() {
+            return String::new();
+        }
+
+        let summary = self
+            .title
+            .as_deref()
+            .map_or("Metadata".to_string(), escape_html);
+        let list = if rows.is_empty() {
+            String::new()
+        } else {
+            format!("<dl>\n{rows}</dl>\n")
+        };
+        format!("<details class=\"frontmatter\" open>\n<summary>{summary}</summary>\n{list}</details>\n")
+    }
+}
+
+fn yaml_strings(value: &Yaml) -> Vec<String> {
+    match value {
+        Yaml::Array(items) => items.iter().flat_map(yaml_strings).collect(),
+        Yaml::String(text) | Yaml::Real(text) => vec![text.trim().to_string()],
+        Yaml::Integer(number) => vec![number.to_string()],
+        Yaml::Boolean(flag) => vec![flag.to_string()],
+        _ => Vec::new(),
+    }
+}
+
+fn toml_strings(value: &toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::Array(items) => items.iter().flat_map(toml_strings).collect(),
+        toml::Value::String(text) => vec![text.trim().to_string()],
+        toml::Value::Table(_) => Vec::new(),
+        other => vec![other.to_string()],
+    }
+}
+
+/// Handles a markdown file that may have been created or modified.
+/// Re-renders tracked files that are cached, re-reads the frontmatter of the
+/// rest, and adds new files in directory mode, sending reload notifications.
+async fn handle_markdown_file_change(
+    path: &Path,
+    state: &SharedMarkdownState,
+    filter: &FileMatcher,
+) {
+    if !is_markdown_file(path) {
+        return;
+    }
+
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let key = canonical
+        .strip_prefix(&state.base_dir)
+        .unwrap_or(&canonical)
+        .to_string_lossy()
+        .to_string();
+
+    let may_add = state.is_directory_mode && !filter.is_ignored(&canonical);
+
+    // Reading and rendering the file blocks
+    let task_state = state.clone();
+    let message = tokio::task::spawn_blocking(move || {
+        let state = &*task_state;
+        let title = |key: &str| {
+            read_lock(&state.tracked_files)
+                .get(key)
+                .map(|t| t.frontmatter.title.clone())
+        };
+        if let Some(old_title) = title(&key) {
+            // Pages nobody has open only need their sidebar entry updated
+            let refreshed = match state.cached_render(&key) {
+                Some(_) => state.reload_file(&key),
+                None => state.refresh_frontmatter(&key),
+            };
+            refreshed.ok()?;
+            // A new title changes the document title and every sidebar label
+            if title(&key) != Some(old_title) {
+                Some(ServerMessage::Reload)
+            } else {
+                Some(ServerMessage::FileChanged { file: key })
+            }
+        } else if may_add && state.add_tracked_file(canonical).is_ok() {
+            // A new file changes the sidebar of every page
+            Some(ServerMessage::Reload)
+        } else {
+            None
+        }
+    })
+    .await;
+    if let Ok(Some(message)) = message {
+        let _ = state.change_tx.send(message);
+    }
+}
+
+/// Collects the base-relative paths of images a rendered page embeds.
+///
+/// Sources are resolved the way the browser resolves them against the page
+/// URL (`/<page>`), so `img/a.png` in `docs/guide.md` becomes `docs/img/a.png`.
+fn referenced_images(page: &str, html: &str) -> HashSet<String> {
+    html.split("src=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .filter_map(|src| resolve_image_src(page, src))
+        .collect()
+}
+
+/// Resolves an image `src` on `page` to a path relative to the base
+/// directory, or `None` for remote, inline and non-image sources.
+fn resolve_image_src(page: &str, src: &str) -> Option<String> {
+    if src.contains("://") || src.starts_with("data:") || !is_image_file(src) {
+        return None;
+    }
+    Some(resolve_page_path(page, src))
+}
+
+/// Resolves `path` the way the browser resolves it against the page URL
+/// (`/<page>`), giving a path relative to the base directory.
+fn resolve_page_path(page: &str, path: &str) -> String {
+    let page_dir = page.rfind('/').map(|pos| &page[..pos]).unwrap_or("");
+    let joined = match path.strip_prefix('/') {
+        Some(absolute) => absolute.to_string(),
+        None if page_dir.is_empty() => path.to_string(),
+        None => format!("{page_dir}/{path}"),
+    };
+    normalize_relative_path(&joined)
+}
+
+/// Points the markdown links (`<a href="…">`) of a rendered page at the
+/// route of their target, so `../api/auth.md` in `guides/intro.md` becomes
+/// `/api/auth.md`. Links whose target isn't tracked are marked with the
+/// `broken-link` class and a tooltip naming the missing file.
+fn resolve_markdown_links(page: &str, html: &str, is_tracked: impl Fn(&str) -> bool) -> String {
+    const LINK_START: &str = "<a href=\"";
+
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find(LINK_START) {
+        let value_start = start + LINK_START.len();
+        output.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+        let end = rest.find('"').unwrap_or(rest.len());
+        let href = &rest[..end];
+        rest = &rest[end..];
+
+        let Some((key, suffix)) = resolve_markdown_link(page, href) else {
+            output.push_str(href);
+            continue;
+        };
+        output.push('/');
+        output.push_str(&key);
+        output.push_str(suffix);
+
+        let unescaped = unescape_html(&key);
+        let target = percent_decode_str(&unescaped).decode_utf8_lossy();
+        if !is_tracked(&target) {
+            // `rest` starts with the closing quote of the last attribute
+            output.push_str(&format!(
+                "\" class=\"broken-link\" title=\"Broken link: {} is not a tracked file",
+                escape_html(&target)
+            ));
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Splits a link to a local markdown file into its base-relative path and
+/// its `#fragment`/`?query` suffix. Returns `None` for any other link.
+fn resolve_markdown_link<'a>(page: &str, href: &'a str) -> Option<(String, &'a str)> {
+    // Fragments, protocol-relative and scheme URLs (`https:`, `mailto:`)
+    if href.is_empty() || href.starts_with('#') || href.starts_with("//") || href.contains(':') {
+        return None;
+    }
+
+    let (path, suffix) = href.split_at(href.find(['#', '?']).unwrap_or(href.len()));
+    is_markdown_file(Path::new(path)).then(|| (resolve_page_path(page, path), suffix))
+}
+
+/// A heading in a rendered document, as listed in the table of contents.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Heading {
+    level: u8,
+    /// Plain text, with markup stripped and entities decoded.
+    text: String,
+    /// Slug used as the heading's `id` attribute.
+    id: String,
+}
+
+/// Gives every markdown heading (`<h1>` … `<h6>`) a slug `id` and collects
+/// the headings in document order. Raw HTML headings that already carry
+/// attributes are left alone.
+fn add_heading_ids(html: &str) -> (String, Vec<Heading>) {
+    let mut output = String::with_capacity(html.len());
+    let mut headings = Vec::new();
+    let mut slugger = Slugger::default();
+    let mut rest = html;
+
+    while let Some(start) = rest.find("<h") {
+        let (before, tag) = rest.split_at(start);
+        output.push_str(before);
+
+        let level = match tag.as_bytes() {
+            [_, _, digit @ b'1'..=b'6', b'>', ..] => digit - b'0',
+            _ => {
+                output.push_str("<h");
+                rest = &tag[2..];
+                continue;
+            }
+        };
+        let close = format!("</h{level}>");
+        let Some(end) = tag.find(&close) else {
+            rest = tag;
+            break;
+        };
+
+        let inner = &tag[4..end];
+        let text = unescape_html(&strip_tags(inner)).trim().to_string();
+        let id = slugger.slug(&text);
+        output.push_str(&format!(
+            "<h{level} id=\"{}\">{inner}{close}",
+            escape_html(&id)
+        ));
+        headings.push(Heading { level, text, id });
+        rest = &tag[end + close.len()..];
+    }
+
+    output.push_str(rest);
+    (output, headings)
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Generates heading slugs the way GitHub does: lowercased, punctuation
+/// dropped, spaces turned into hyphens, and repeats suffixed with `-1`, `-2`…
+#[derive(Default)]
+struct Slugger {
+    used: HashSet<String>,
+}
+
+impl Slugger {
+    fn slug(&mut self, text: &str) -> String {
+        let base: String = text
+            .to_lowercase()
+            .chars()
+            .filter_map(|c| match c {
+                ' ' => Some('-'),
+                '-' | '_' => Some(c),
+                _ if c.is_alphanumeric() => Some(c),
+                _ => None,
+            })
+            .collect();
+
+        let mut slug = base.clone();
+        let mut count = 0;
+        while !self.used.insert(slug.clone()) {
+            count += 1;
+            slug = format!("{base}-{count}");
+        }
+        slug
+    }
+}
+
+/// Plain text of the part of a document under one heading (or before the
+/// first heading), as kept in the search index.
+#[derive(Debug, Clone, PartialEq)]
+struct Section {
+    heading: Option<Heading>,
+    text: String,
+}
+
+/// Splits a rendered body into sections at the headings found by
+/// `add_heading_ids`, keeping their whitespace-collapsed plain text.
+fn split_sections(html: &str, headings: &[Heading]) -> Vec<Section> {
+    let mut sections = Vec::new();
+    let mut heading = None;
+    let mut rest = html;
+
+    for next in headings {
+        let open = format!("<h{} id=\"{}\">", next.level, escape_html(&next.id));
+        let Some(start) = rest.find(&open) else {
+            continue;
+        };
+        sections.push(Section {
+            heading: heading.replace(next.clone()),
+            text: plain_text(&rest[..start]),
+        });
+
+        let close = format!("</h{}>", next.level);
+        rest = &rest[start..];
+        rest = rest
+            .find(&close)
+            .map_or("", |end| &rest[end + close.len()..]);
+    }
+    sections.push(Section {
+        heading,
+        text: plain_text(rest),
+    });
+
+    sections.retain(|section| section.heading.is_some() || !section.text.is_empty());
+    sections
+}
+
+fn plain_text(html: &str) -> String {
+    unescape_html(&strip_tags(html))
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+const MAX_SEARCH_HITS: usize = 50;
+const SNIPPET_CONTEXT: usize = 80;
+
+/// A tracked file matching a search, as returned by `/api/search`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SearchHit {
+    file: String,
+    /// Frontmatter title, or the file name.
+    title: String,
+    /// Heading of the best-matching section, if it has one.
+    heading: Option<String>,
+    /// Slug of that heading, for linking to `/<file>#<anchor>`.
+    anchor: Option<String>,
+    /// Text around the first match in that section.
+    snippet: String,
+    score: usize,
+}
+
+/// Scores one file against lowercase `terms`. Matches in the title and in
+/// headings count more than matches in body text; files missing any term
+/// don't match at all.
+fn search_file(key: &str, tracked: &TrackedFile, terms: &[String]) -> Option<SearchHit> {
+    let title = tracked
+        .frontmatter
+        .title
+        .clone()
+        .unwrap_or_else(|| key.rsplit('/').next().unwrap_or(key).to_string());
+    let title_lower = title.to_lowercase();
+
+    let mut found = vec![false; terms.len()];
+    let mut best: Option<(usize, &Section)> = None;
+    let mut score = 0;
+
+    let sections = tracked.sections.as_deref().unwrap_or_default();
+    for section in sections {
+        let heading_lower = section
+            .heading
+            .as_ref()
+            .map(|heading| heading.text.to_lowercase())
+            .unwrap_or_default();
+        let text_lower = section.text.to_lowercase();
+
+        let mut section_score = 0;
+        for (term, found) in terms.iter().zip(found.iter_mut()) {
+            let matches = 5 * heading_lower.matches(term.as_str()).count()
+                + text_lower.matches(term.as_str()).count();
+            *found |= matches > 0;
+            section_score += matches;
+        }
+        score += section_score;
+        if section_score > best.map_or(0, |(best_score, _)| best_score) {
+            best = Some((section_score, section));
+        }
+    }
+
+    for (term, found) in terms.iter().zip(found.iter_mut()) {
+        if title_lower.contains(term.as_str()) {
+            *found = true;
+            score += 10;
+        }
+    }
+    if !found.iter().all(|found| *found) {
+        return None;
+    }
+
+    // A file matched by its title alone is previewed from the top
+    let best = best.map(|(_, section)| section);
+    let heading = best.and_then(|section| section.heading.as_ref());
+    let preview = best.or(sections.first());
+    Some(SearchHit {
+        file: key.to_string(),
+        title,
+        heading: heading.map(|heading| heading.text.clone()),
+        anchor: heading.map(|heading| heading.id.clone()),
+        snippet: preview.map_or_else(String::new, |section| snippet(&section.text, terms)),
+        score,
+    })
+}
+
+/// Cuts the text around the first occurrence of any term, on word
+/// boundaries, marking elided ends with `…`.
+fn snippet(text: &str, terms: &[String]) -> String {
+    let first_match = first_match(text, terms);
+
+    let mut start = first_match.saturating_sub(SNIPPET_CONTEXT);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    if start > 0 {
+        start = text[start..first_match]
+            .find(' ')
+            .map_or(start, |space| start + space + 1);
+    }
+
+    let mut end = (first_match + 2 * SNIPPET_CONTEXT).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    if end < text.len() {
+        end = text[..end]
+            .rfind(' ')
+            .filter(|&space| space > first_match)
+            .unwrap_or(end);
+    }
+
+    let prefix = if start > 0 { "…" } else { "" };
+    let suffix = if end < text.len() { "…" } else { "" };
+    format!("{prefix}{}{suffix}", &text[start..end])
+}
+
+/// Byte offset in `text` of the first occurrence of any lowercase term,
+/// ignoring case; 0 when there is none.
+fn first_match(text: &str, terms: &[String]) -> usize {
+    let lower = text.to_lowercase();
+    let Some(position) = terms
+        .iter()
+        .filter_map(|term| lower.find(term.as_str()))
+        .min()
+    else {
+        return 0;
+    };
+    // Lowercasing can change how many bytes a character takes
+    let mut lower_offset = 0;
+    for (offset, c) in text.char_indices() {
+        if lower_offset >= position {
+            return offset;
+        }
+        lower_offset += c.to_lowercase().map(char::len_utf8).sum::<usize>();
+    }
+    text.len()
+}
+
+/// A problem found by the link check, located in the markdown source.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct CheckIssue {
+    file: String,
+    line: usize,
+    column: usize,
+    kind: CheckIssueKind,
+    /// The link or image URL as written.
+    target: String,
+    message: String,
+}
+
+impl std::fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum CheckIssueKind {
+    /// The target file isn't tracked (markdown) or wouldn't be served.
+    BrokenLink,
+    /// The target file exists but has no element with the fragment's id.
+    MissingAnchor,
+    /// The image wouldn't be served.
+    MissingImage,
+}
+
+/// A link or image URL in the markdown source and where it was written.
+struct Reference {
+    url: String,
+    line: usize,
+    column: usize,
+    is_image: bool,
+}
+
+/// Collects the URLs of links, images and link reference definitions.
+fn collect_references(node: &markdown::mdast::Node, references: &mut Vec<Reference>) {
+    use markdown::mdast::Node;
+
+    let target = match node {
+        Node::Link(link) => Some((&link.url, false)),
+        Node::Definition(definition) => Some((&definition.url, false)),
+        Node::Image(image) => Some((&image.url, true)),
+        _ => None,
+    };
+    if let (Some((url, is_image)), Some(position)) = (target, node.position()) {
+        references.push(Reference {
+            url: url.clone(),
+            line: position.start.line,
+            column: position.start.column,
+            is_image,
+        });
+    }
+
+    for child in node.children().into_iter().flatten() {
+        collect_references(child, references);
+    }
+}
+
+/// Values of the `id` attributes in rendered HTML, still HTML-escaped.
+fn element_ids(html: &str) -> impl Iterator<Item = &str> {
+    html.split(" id=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+}
+
+/// Elements that never have a closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Splits rendered HTML into its top-level elements, in the same order the
+/// browser exposes them as children of `#content`. Text between elements is
+/// skipped since it doesn't count as a child element.
+fn top_level_blocks(html: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut depth = 0usize;
+    let mut block_start = 0;
+    let mut pos = 0;
+
+    while let Some(offset) = html[pos..].find('<') {
+        let tag_start = pos + offset;
+        let rest = &html[tag_start..];
+
+        if rest.starts_with("<!--") {
+            pos = rest
+                .find("-->")
+                .map_or(html.len(), |end| tag_start + end + 3);
+            continue;
+        }
+
+        let tag_end = rest.find('>').map_or(html.len(), |end| tag_start + end + 1);
+        let tag = html[tag_start + 1..tag_end].trim_end_matches('>');
+        pos = tag_end;
+
+        if let Some(closing) = tag.strip_prefix('/') {
+            if closing.is_empty() || depth == 0 {
+                continue;
+            }
+            depth -= 1;
+            if depth == 0 {
+                blocks.push(&html[block_start..tag_end]);
+            }
+            continue;
+        }
+
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        if name.is_empty() || name.starts_with('!') {
+            continue;
+        }
+
+        if depth == 0 {
+            block_start = tag_start;
+        }
+        if tag.ends_with('/') || VOID_ELEMENTS.contains(&name.as_str()) {
+            if depth == 0 {
+                blocks.push(&html[block_start..tag_end]);
+            }
+        } else {
+            depth += 1;
+        }
+    }
+
+    blocks
+}
+
+/// Finds the first top-level block that differs between two renders.
+///
+/// Returns `None` when the blocks are identical. When blocks were only removed
+/// from the end, points at the new last block since that's where the edit was.
+fn first_changed_block(old_html: &str, new_html: &str) -> Option<usize> {
+    let old_blocks = top_level_blocks(old_html);
+    let new_blocks = top_level_blocks(new_html);
+
+    let common = old_blocks
+        .iter()
+        .zip(&new_blocks)
+        .take_while(|(old, new)| old == new)
+        .count();
+
+    if common == old_blocks.len() && common == new_blocks.len() {
+        None
+    } else if common < new_blocks.len() {
+        Some(common)
+    } else {
+        new_blocks.len().checked_sub(1)
+    }
+}
+
+const CODE_BLOCK_OPEN: &str = "<pre><code class=\"language-";
+const CODE_BLOCK_CLOSE: &str = "</code></pre>";
+const DISPLAY_MATH_OPEN: &str = "<pre><code class=\"language-math math-display\">";
+const FENCED_MATH_OPEN: &str = "<pre><code class=\"language-math\">";
+const INLINE_MATH_OPEN: &str = "<code class=\"language-math math-inline\">";
+const INLINE_MATH_CLOSE: &str = "</code>";
+
+/// Replaces every `open`…`close` span of `html` with what `rewrite` returns
+/// for the text between them, keeping spans it returns `None` for.
+fn rewrite_elements(
+    html: &str,
+    open: &str,
+    close: &str,
+    mut rewrite: impl FnMut(&str) -> Option<String>,
+) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find(open) {
+        let (before, element) = rest.split_at(start);
+        output.push_str(before);
+
+        let Some(end) = element.find(close) else {
+            rest = element;
+            break;
+        };
+        let (element, after) = element.split_at(end + close.len());
+        rest = after;
+
+        let inner = &element[open.len()..element.len() - close.len()];
+        output.push_str(rewrite(inner).as_deref().unwrap_or(element));
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Highlights fenced code blocks whose info string names a known language.
+///
+/// Tokens are emitted as `hl-`-prefixed scope classes rather than inline
+/// colours, so the template's theme variables decide how they look.
+fn highlight_code_blocks(html: &str) -> String {
+    rewrite_elements(html, CODE_BLOCK_OPEN, CODE_BLOCK_CLOSE, |block| {
+        let (language, code) = block.split_once("\">")?;
+        let syntax = find_syntax(language)?;
+        let highlighted = highlight_code(&unescape_html(code), syntax)?;
+        Some(format!(
+            "{CODE_BLOCK_OPEN}{language}\">{highlighted}{CODE_BLOCK_CLOSE}"
+        ))
+    })
+}
+
+/// Renders `$…$`, `$$…$$` and ` ```math ` blocks to MathML, which browsers
+/// display natively without any script.
+fn render_math(html: &str) -> String {
+    let html = rewrite_elements(html, DISPLAY_MATH_OPEN, CODE_BLOCK_CLOSE, |tex| {
+        latex_to_mathml(tex, DisplayMode::Block)
+    });
+    let html = rewrite_elements(&html, FENCED_MATH_OPEN, CODE_BLOCK_CLOSE, |tex| {
+        latex_to_mathml(tex, DisplayMode::Block)
+    });
+    rewrite_elements(&html, INLINE_MATH_OPEN, INLINE_MATH_CLOSE, |tex| {
+        latex_to_mathml(tex, DisplayMode::Inline)
+    })
+}
+
+/// Converts HTML-escaped LaTeX to MathML, keeping the source as an annotation
+/// so copying the formula yields TeX. Invalid commands are rendered in an
+/// error colour rather than failing the whole expression.
+fn latex_to_mathml(escaped_tex: &str, display_mode: DisplayMode) -> Option<String> {
+    let escaped_tex = escaped_tex.trim();
+    let tex = unescape_html(escaped_tex);
+
+    let storage = Storage::new();
+    let parser = Parser::new(&tex, &storage);
+    let config = RenderConfig {
+        display_mode,
+        annotation: Some(escaped_tex),
+        ..RenderConfig::default()
+    };
+
+    let mut mathml = String::new();
+    push_mathml(&mut mathml, parser, config).ok()?;
+    Some(escape_stray_lt(&mathml))
+}
+
+/// Escapes `<` characters that don't start a tag. The MathML renderer writes
+/// operators such as `<` verbatim, which would otherwise confuse the HTML parser.
+fn escape_stray_lt(markup: &str) -> String {
+    let mut escaped = String::with_capacity(markup.len());
+    let mut chars = markup.chars().peekable();
+    while let Some(c) = chars.next() {
+        let starts_tag = chars
+            .peek()
+            .is_some_and(|next| next.is_ascii_alphabetic() || *next == '/');
+        if c == '<' && !starts_tag {
+            escaped.push_str("&lt;");
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Looks up a syntax by fence language, accepting common aliases the bundled
+/// syntaxes don't know. Mermaid blocks are left alone for the client.
+fn find_syntax(language: &str) -> Option<&'static SyntaxReference> {
+    // Info strings like `rust,ignore` carry extra attributes after the language
+    let language = language.split(',').next().unwrap_or(language);
+    let language = match language.to_ascii_lowercase().as_str() {
+        "mermaid" => return None,
+        "ts" | "typescript" | "tsx" | "jsx" => "js".to_string(),
+        "shell" | "console" | "zsh" => "sh".to_string(),
+        other => other.to_string(),
+    };
+    syntax_set().find_syntax_by_token(&language)
+}
+
+fn highlight_code(code: &str, syntax: &SyntaxReference) -> Option<String> {
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(
+        syntax,
+        syntax_set(),
+        ClassStyle::SpacedPrefixed { prefix: "hl-" },
+    );
+    for line in LinesWithEndings::from(code) {
+        generator
+            .parse_html_for_line_which_includes_newline(line)
+            .ok()?;
+    }
+    Some(generator.finalize())
+}
+
+/// Keeps JavaScript inlined into a `<script>` element from closing it early:
+/// HTML ends the element at any `</script`, whatever its case, so each one
+/// becomes the equivalent `<\/script`.
+fn escape_inline_script(js: &str) -> String {
+    let mut escaped = String::with_capacity(js.len());
+    let mut rest = js;
+    while let Some(index) = rest.find("</") {
+        let (before, tag) = rest.split_at(index + 1);
+        escaped.push_str(before);
+        if tag
+            .get(1..7)
+            .is_some_and(|name| name.eq_ignore_ascii_case("script"))
+        {
+            escaped.push('\\');
+        }
+        rest = tag;
+    }
+    escaped.push_str(rest);
+    escaped
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Reverses the escaping markdown-rs applies to code block contents.
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// Marks inserted, deleted and modified top-level blocks of `new_html`
+/// relative to `old_html`.
+///
+/// Each changed block is wrapped in a `diff-block` element. Deleted blocks are
+/// kept in place so the reader sees what was removed.
+fn annotate_changes(old_html: &str, new_html: &str) -> String {
+    let old_blocks = top_level_blocks(old_html);
+    let new_blocks = top_level_blocks(new_html);
+
+    let mut annotated = String::with_capacity(new_html.len());
+    let mut push_block = |kind: Option<&str>, block: &str| match kind {
+        Some(kind) => annotated.push_str(&format!(
+            "<div class=\"diff-block diff-{kind}\" title=\"{kind}\">\n{block}\n</div>\n"
+        )),
+        None => {
+            annotated.push_str(block);
+            annotated.push('\n');
+        }
+    };
+
+    for op in similar::capture_diff_slices(similar::Algorithm::Myers, &old_blocks, &new_blocks) {
+        match op {
+            similar::DiffOp::Equal { new_index, len, .. } => {
+                for block in &new_blocks[new_index..new_index + len] {
+                    push_block(None, block);
+                }
+            }
+            similar::DiffOp::Delete {
+                old_index, old_len, ..
+            } => {
+                for block in &old_blocks[old_index..old_index + old_len] {
+                    push_block(Some("deleted"), block);
+                }
+            }
+            similar::DiffOp::Insert {
+                new_index, new_len, ..
+            } => {
+                for block in &new_blocks[new_index..new_index + new_len] {
+                    push_block(Some("inserted"), block);
+                }
+            }
+            similar::DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => {
+                // Pair replaced blocks up as modifications; any surplus on
+                // either side is a plain deletion or insertion
+                let modified = old_len.min(new_len);
+                for block in &new_blocks[new_index..new_index + modified] {
+                    push_block(Some("modified"), block);
+                }
+                for block in &old_blocks[old_index + modified..old_index + old_len] {
+                    push_block(Some("deleted"), block);
+                }
+                for block in &new_blocks[new_index + modified..new_index + new_len] {
+                    push_block(Some("inserted"), block);
+                }
+            }
+        }
+    }
+
+    annotated
+}
+
+/// Resolves `.` and `..` segments in a slash-separated relative path.
+fn normalize_relative_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+/// Applies a watcher event to the state. Returns `true` when something may
+/// have disappeared, so the caller should schedule `reconcile_removed_files`.
+async fn handle_file_event(
+    event: Event,
+    state: &SharedMarkdownState,
+    filter: &FileMatcher,
+) -> bool {
+    match event.kind {
+        notify::EventKind::Modify(notify::event::ModifyKind::Name(rename_mode)) => {
+            use notify::event::RenameMode;
+            match rename_mode {
+                RenameMode::Both if event.paths.len() == 2 => {
+                    // Linux/Windows: Both old and new paths provided in single event
+                    let new_path = &event.paths[1];
+                    handle_new_path(new_path, state, filter).await;
+                    true
+                }
+                // File being renamed away
+                RenameMode::From => true,
+                RenameMode::To => {
+                    // File renamed to this location
+                    if let Some(path) = event.paths.first() {
+                        handle_new_path(path, state, filter).await;
+                    }
+                    false
+                }
+                RenameMode::Any => {
+                    // macOS: Sends separate events for old and new paths
+                    // Use file existence to distinguish old (doesn't exist) from new (exists)
+                    match event.paths.first() {
+                        Some(path) if path.exists() => {
+                            handle_new_path(path, state, filter).await;
+                            false
+                        }
+                        _ => true,
+                    }
+                }
+                _ => false,
+            }
+        }
+        _ => {
+            for path in &event.paths {
+                if is_markdown_file(path) {
+                    match event.kind {
+                        notify::EventKind::Create(_)
+                        | notify::EventKind::Modify(notify::event::ModifyKind::Data(_))
+                        | notify::EventKind::Modify(notify::event::ModifyKind::Metadata(
+                            notify::event::MetadataKind::WriteTime,
+                        )) => {
+                            handle_markdown_file_change(path, state, filter).await;
+                        }
+                        notify::EventKind::Remove(_) => {
+                            // Don't remove files from tracking right away. Editors like
+                            // neovim save by renaming the file to a backup, then creating
+                            // a new one. If we removed the file here, HTTP requests during
+                            // that window would see empty tracked_files and return 404.
+                        }
+                        _ => {}
+                    }
+                } else if path.is_file() && is_image_file(path.to_str().unwrap_or("")) {
+                    match event.kind {
+                        notify::EventKind::Modify(_)
+                        | notify::EventKind::Create(_)
+                        | notify::EventKind::Remove(_) => {
+                            let image_path = path
+                                .strip_prefix(&state.base_dir)
+                                .unwrap_or(path)
+                                .to_string_lossy()
+                                .to_string();
+                            let _ = state
+                                .change_tx
+                                .send(ServerMessage::ImageChanged { path: image_path });
+                        }
+                        _ => {}
+                    }
+                } else if matches!(event.kind, notify::EventKind::Create(_)) && path.is_dir() {
+                    // Files may have been added before the directory was watched
+                    handle_new_path(path, state, filter).await;
+                }
+            }
+            // Removing a directory may only report the directory itself
+            matches!(event.kind, notify::EventKind::Remove(_))
+        }
+    }
+}
+
+/// Handles the new path of a rename or a created directory: a markdown
+/// file, or a directory whose markdown files are new or now live under a new
+/// name.
+async fn handle_new_path(path: &Path, state: &SharedMarkdownState, filter: &FileMatcher) {
+    if !path.is_dir() {
+        handle_markdown_file_change(path, state, filter).await;
+        return;
+    }
+    if filter.is_ignored_dir(path) {
+        return;
+    }
+
+    let Ok(files) = scan_markdown_files(path, &FileFilter::default()) else {
+        return;
+    };
+    for file in files {
+        handle_markdown_file_change(&file, state, filter).await;
+    }
+}
+
+/// Characters escaped when a tracked file's key is put into a URL path.
+const URL_PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// How long a tracked file may be missing before it is dropped. Editors
+/// that save by renaming and recreating the file finish well within this.
+const REMOVAL_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+/// Drops tracked files that no longer exist, in directory mode, and reloads
+/// every page since the sidebar changed.
+async fn reconcile_removed_files(state: &SharedMarkdownState) {
+    if state.is_directory_mode && state.remove_missing_files() {
+        let _ = state.change_tx.send(ServerMessage::Reload);
+    }
+}
+
+/// Server settings from the command line beyond what to serve.
+#[derive(Debug, Clone)]
+pub(crate) struct ServeOptions {
+    /// Which files directory mode picks up as they appear.
+    pub(crate) filter: FileFilter,
+    /// How long the watcher waits for a burst of file events to settle
+    /// before processing it; zero handles every event as it arrives.
+    pub(crate) debounce: Duration,
+    /// Poll for changes at this interval instead of using OS notifications.
+    pub(crate) poll: Option<Duration>,
+    /// How many rendered pages are kept in memory.
+    pub(crate) render_cache: NonZeroUsize,
+    /// Render pages in the background at startup, up to `render_cache` of
+    /// them, instead of waiting for their first request.
+    pub(crate) prewarm: bool,
+    /// Credentials every request must carry; `None` serves anyone.
+    pub(crate) auth: Option<Auth>,
+    /// Names requests may address the server by.
+    pub(crate) allowed_hosts: AllowedHosts,
+    /// Origins allowed to make cross-origin requests; none by default.
+    pub(crate) cors_origins: Vec<String>,
+    /// Serve HTTPS with this certificate instead of plain HTTP.
+    pub(crate) tls: Option<Tls>,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            filter: FileFilter::default(),
+            debounce: DEFAULT_DEBOUNCE,
+            poll: None,
+            render_cache: DEFAULT_RENDER_CACHE,
+            prewarm: false,
+            auth: None,
+            allowed_hosts: AllowedHosts::default(),
+            cors_origins: Vec::new(),
+            tls: None,
+        }
+    }
+}
+
+/// Where the HTTPS certificate comes from.
+#[derive(Debug, Clone)]
+pub(crate) enum Tls {
+    /// PEM certificate chain and private key files.
+    Files { cert: PathBuf, key: PathBuf },
+    /// A certificate generated at startup, which browsers ask to accept once.
+    SelfSigned,
+}
+
+impl Tls {
+    /// Loads, or generates, the certificate for a server bound to `hostname`
+    /// and addressed by `allowed_hosts`.
+    async fn config(&self, hostname: &str, allowed_hosts: &AllowedHosts) -> Result<RustlsConfig> {
+        // Fails harmlessly if a provider is already installed
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        match self {
+            Tls::Files { cert, key } => {
+                RustlsConfig::from_pem_file(cert, key)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "failed to load TLS certificate {} and key {}",
+                            cert.display(),
+                            key.display()
+                        )
+                    })
+            }
+            Tls::SelfSigned => {
+                let names = self_signed_names(hostname, allowed_hosts, &interface_addresses());
+                let certified = rcgen::generate_simple_self_signed(names)
+                    .context("failed to generate a self-signed certificate")?;
+                RustlsConfig::from_der(
+                    vec![certified.cert.der().to_vec()],
+                    certified.key_pair.serialize_der(),
+                )
+                .await
+                .context("failed to use the self-signed certificate")
+            }
+        }
+    }
+}
+
+/// Names a self-signed certificate is issued for: loopback, the bind
+/// hostname unless it is a wildcard address, every allowed host name, and
+/// the machine's `interfaces`, which other machines reach it by.
+fn self_signed_names(
+    hostname: &str,
+    allowed_hosts: &AllowedHosts,
+    interfaces: &[IpAddr],
+) -> Vec<String> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    let hostname = hostname.to_ascii_lowercase();
+    let bound = (browsable_host(&hostname) == hostname).then_some(hostname);
+    let addresses = interfaces.iter().map(IpAddr::to_string);
+    for name in bound
+        .into_iter()
+        .chain(allowed_hosts.names.iter().cloned())
+        .chain(addresses)
+    {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Addresses of the interfaces the default IPv4 and IPv6 routes leave
+/// through, which other machines on the network reach the server by.
+/// Addresses of other interfaces can be named with `--allowed-host`.
+fn interface_addresses() -> Vec<IpAddr> {
+    // Connecting a UDP socket only picks a route; nothing is sent
+    let route = |unspecified: IpAddr, remote: IpAddr| {
+        let socket = std::net::UdpSocket::bind((unspecified, 0))?;
+        socket.connect((remote, 9))?;
+        socket.local_addr()
+    };
+    let mut addresses = Vec::new();
+    for address in [
+        route(
+            Ipv4Addr::UNSPECIFIED.into(),
+            Ipv4Addr::new(192, 0, 2, 1).into(),
+        ),
+        route(
+            Ipv6Addr::UNSPECIFIED.into(),
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|address| address.ip())
+    {
+        // URLs can't use IPv6 link-local addresses without naming the interface
+        let link_local = matches!(address, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80);
+        if !address.is_loopback() && !address.is_unspecified() && !link_local {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+/// Host names requests may address the server by. A page on another domain
+/// that DNS rebinding has pointed at the server still sends its own domain
+/// as `Host`, so it is turned away. IP addresses can't be rebound and are
+/// always accepted.
+#[derive(Debug, Clone)]
+pub(crate) struct AllowedHosts {
+    names: Vec<String>,
+    /// Accept any `Host`, for `--allowed-host '*'`.
+    any: bool,
+}
+
+impl Default for AllowedHosts {
+    fn default() -> Self {
+        AllowedHosts {
+            names: vec!["localhost".to_string()],
+            any: false,
+        }
+    }
+}
+
+impl AllowedHosts {
+    /// Accepts `localhost` and `names`; a `*` among them turns the check off.
+    /// Addresses among them are kept too, for the self-signed certificate.
+    pub(crate) fn new(names: Vec<String>) -> Self {
+        let mut allowed = AllowedHosts::default();
+        for name in names {
+            if name == "*" {
+                allowed.any = true;
+            } else {
+                allowed.names.push(host_name(&name).to_ascii_lowercase());
+            }
+        }
+        allowed
+    }
+
+    /// Also accepts `hostname`, the name the server is bound to, if it is
+    /// one rather than an IP address.
+    fn allow(&mut self, hostname: &str) {
+        let name = host_name(hostname);
+        if name.parse::<IpAddr>().is_err() {
+            self.names.push(name.to_ascii_lowercase());
+        }
+    }
+
+    /// Whether a `Host` header value names the server.
+    fn allows(&self, host: &str) -> bool {
+        self.any || host_name(host).parse::<IpAddr>().is_ok() || self.is_named(host)
+    }
+
+    /// Whether `host` is one of those listed, leaving out the unlisted IP
+    /// addresses and `*` that `allows` also accepts.
+    fn is_named(&self, host: &str) -> bool {
+        let name = host_name(host);
+        self.names
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The name in a `Host` value, without port, IPv6 brackets or trailing dot:
+/// `[::1]:3000` gives `::1`, `localhost.:3000` gives `localhost`.
+fn host_name(host: &str) -> &str {
+    let name = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or(bracketed),
+        // A bare IPv6 address has colons but no port
+        None if host.parse::<Ipv6Addr>().is_ok() => host,
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.strip_suffix('.').unwrap_or(name)
+}
+
+/// The host a request was sent to.
+fn request_host(request: &Request) -> Option<&str> {
+    // HTTP/2 requests carry the host in the URI rather than a header
+    match request.headers().get(header::HOST) {
+        Some(host) => Some(host.to_str().unwrap_or_default()),
+        None => request
+            .uri()
+            .authority()
+            .map(|authority| authority.as_str()),
+    }
+}
+
+/// Refuses requests whose `Host` the server doesn't answer to.
+async fn validate_host(
+    State(allowed): State<Arc<AllowedHosts>>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    if request_host(&request).is_some_and(|host| !allowed.allows(host)) {
+        return (
+            StatusCode::FORBIDDEN,
+            "Unknown host: pass --allowed-host to serve under this name",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Origins whose pages may open a WebSocket besides the server's own.
+struct SocketOrigins {
+    allowed_hosts: AllowedHosts,
+    cors_origins: Vec<String>,
+}
+
+impl SocketOrigins {
+    /// Whether a request's `Origin`, when it has one, is the server itself
+    /// (the host and port the request was sent to), a `--cors-origin`, or a
+    /// name given to `--allowed-host`, such as the one a proxy serves it
+    /// under. An IP address proves nothing about a page's origin, so unlike
+    /// `Host` it must match exactly.
+    fn allows(&self, request: &Request) -> bool {
+        let Some(origin) = request.headers().get(header::ORIGIN) else {
+            return true;
+        };
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        if self
+            .cors_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        {
+            return true;
+        }
+        let Some((_, authority)) = origin.split_once("://") else {
+            return false;
+        };
+        request_host(request).is_some_and(|host| authority.eq_ignore_ascii_case(host))
+            || self.allowed_hosts.is_named(authority)
+    }
+}
+
+/// Refuses WebSocket connections from pages of other origins. Browsers let
+/// any page open one, cookies and all, and only say where it came from in
+/// `Origin`.
+async fn reject_cross_origin(
+    State(origins): State<Arc<SocketOrigins>>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    if !origins.allows(&request) {
+        return (
+            StatusCode::FORBIDDEN,
+            "Cross-origin WebSocket connections are not allowed",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Access control for every route, `/ws` included.
+#[derive(Debug, Clone)]
+pub(crate) enum Auth {
+    /// HTTP basic authentication with `user:password` credentials.
+    Basic(String),
+    /// A secret presented once in the `?token=` query of the URL mdserve
+    /// prints and opens, then remembered by the browser as a cookie.
+    Token(String),
+}
+
+/// Cookie holding the token once the browser has presented it.
+const TOKEN_COOKIE: &str = "mdserve_token";
+
+/// What `require_auth` checks requests against.
+struct AuthState {
+    auth: Auth,
+    /// Whether the server is reached over HTTPS, so the token cookie must
+    /// never be sent in the clear.
+    https: bool,
+}
+
+impl Auth {
+    /// Parses `--auth` credentials.
+    pub(crate) fn basic(credentials: &str) -> Result<Self> {
+        match credentials.split_once(':') {
+            Some((user, password)) if !user.is_empty() && !password.is_empty() => {
+                Ok(Auth::Basic(credentials.to_string()))
+            }
+            _ => anyhow::bail!("--auth expects USER:PASSWORD"),
+        }
+    }
+
+    /// A fresh random 256-bit token.
+    pub(crate) fn random_token() -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        getrandom::fill(&mut bytes)
+            .map_err(|e| anyhow::anyhow!("failed to generate a token: {e}"))?;
+        Ok(Auth::Token(BASE64_URL_SAFE_NO_PAD.encode(bytes)))
+    }
+
+    /// Path and query of the URL to print and open, which carries the token.
+    fn start_path(&self) -> String {
+        match self {
+            Auth::Basic(_) => String::new(),
+            Auth::Token(token) => format!("/?token={token}"),
+        }
+    }
+}
+
+/// Rejects requests without valid credentials. In token mode a request
+/// carrying the token in its query is let through; a page request is
+/// redirected to the same URL without it, setting the cookie on the way.
+async fn require_auth(
+    State(state): State<Arc<AuthState>>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let token = match &state.auth {
+        Auth::Basic(credentials) => {
+            let expected = format!("Basic {}", BASE64_STANDARD.encode(credentials));
+            let authorized = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| constant_time_eq(value, &expected));
+            if authorized {
+                return next.run(request).await;
+            }
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, r#"Basic realm="mdserve""#)],
+                "Authentication required",
+            )
+                .into_response();
+        }
+        Auth::Token(token) => token,
+    };
+
+    let has_cookie = request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .any(|(name, value)| name == TOKEN_COOKIE && constant_time_eq(value, token));
+    if has_cookie {
+        return next.run(request).await;
+    }
+
+    let query = request.uri().query().unwrap_or("");
+    let (presented, rest): (Vec<&str>, Vec<&str>) = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .partition(|pair| pair.starts_with("token="));
+    let has_token = presented
+        .iter()
+        .any(|pair| constant_time_eq(&pair["token=".len()..], token));
+    if !has_token {
+        return (
+            StatusCode::UNAUTHORIZED,
+            "Authentication required: open the URL mdserve printed at startup",
+        )
+            .into_response();
+    }
+    // A WebSocket upgrade can't follow a redirect
+    if request.headers().contains_key(header::UPGRADE) {
+        return next.run(request).await;
+    }
+
+    let mut location = request.uri().path().to_string();
+    if !rest.is_empty() {
+        location = format!("{location}?{}", rest.join("&"));
+    }
+    // Lax, so the cookie also comes along when a link in another app or
+    // site opens a page, as the printed URL's token only works once
+    let mut cookie = format!("{TOKEN_COOKIE}={token}; Path=/; HttpOnly; SameSite=Lax");
+    if state.https {
+        cookie.push_str("; Secure");
+    }
+    ([(header::SET_COOKIE, cookie)], Redirect::to(&location)).into_response()
+}
+
+/// Compares secrets in time independent of where they first differ.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+pub(crate) const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// A burst is cut off after this many debounce windows, so a file that is
+/// written continuously still shows up.
+const MAX_BURST_WINDOWS: u32 = 10;
+
+/// Polling interval when the OS runs out of file watches.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the OS backend gets one recursive watch instead of one per
+/// directory. FSEvents restarts its stream for every watch added and Windows
+/// holds a handle per watched directory, while a recursive watch costs them
+/// nothing extra; events from ignored directories are dropped as they arrive.
+const RECURSIVE_WATCH: bool = cfg!(any(target_os = "macos", target_os = "windows"));
+
+type WatchResult = std::result::Result<Event, notify::Error>;
+
+/// Starts watching `base_dir` and the directories below it that `filter`
+/// doesn't ignore, sending events to `tx`. Uses the OS notification backend
+/// unless `poll` is set, and falls back to polling when the OS file watch
+/// limit is already exhausted. See `RECURSIVE_WATCH` for backends that watch
+/// the whole tree.
+fn start_watcher(
+    base_dir: &Path,
+    tx: &mpsc::UnboundedSender<WatchResult>,
+    poll: Option<Duration>,
+    filter: &FileMatcher,
+) -> Result<Box<dyn Watcher + Send>> {
+    if let Some(interval) = poll {
+        return start_poll_watcher(base_dir, tx, interval, filter);
+    }
+
+    let mut watcher = RecommendedWatcher::new(forward_events(tx), Config::default())?;
+    let watched = if RECURSIVE_WATCH {
+        watcher.watch(base_dir, RecursiveMode::Recursive)
+    } else {
+        watch_tree(&mut watcher, base_dir, filter)
+    };
+    match watched {
+        Ok(()) => Ok(Box::new(watcher)),
+        Err(e) if is_watch_limit(&e) => {
+            print_poll_fallback();
+            start_poll_watcher(base_dir, tx, DEFAULT_POLL_INTERVAL, filter)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Watches by comparing modification times every `interval`, which also
+/// works on network and container mounts that never deliver notifications.
+/// Ignored directories are left out of the scan like they are for OS
+/// watches.
+fn start_poll_watcher(
+    base_dir: &Path,
+    tx: &mpsc::UnboundedSender<WatchResult>,
+    interval: Duration,
+    filter: &FileMatcher,
+) -> Result<Box<dyn Watcher + Send>> {
+    let config = Config::default().with_poll_interval(interval);
+    let mut watcher = PollWatcher::new(forward_events(tx), config)?;
+    watch_tree(&mut watcher, base_dir, filter)?;
+    Ok(Box::new(watcher))
+}
+
+/// Watches `dir` and every directory below it that `filter` doesn't ignore,
+/// one directory at a time, so ignored trees such as `node_modules` or
+/// `target` cost no watches. Directories created later are added by the
+/// watcher task as they appear.
+fn watch_tree(watcher: &mut dyn Watcher, dir: &Path, filter: &FileMatcher) -> notify::Result<()> {
+    let mut visited = HashSet::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        // Symlinks are followed like the scan does, but only once each
+        if !visited.insert(dir.canonicalize().unwrap_or_else(|_| dir.clone())) {
+            continue;
+        }
+        match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {}
+            // Removed since it was listed
+            Err(e) if matches!(e.kind, notify::ErrorKind::PathNotFound) => continue,
+            Err(e) => return Err(e),
+        }
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        pending.extend(
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir() && !filter.is_ignored_dir(path)),
+        );
+    }
+    Ok(())
+}
 
-        let html_body = markdown::to_html_with_options(content, &options)
-            .unwrap_or_else(|_| "Error parsing markdown".to_string());
+/// Directories an event reports as new: created, or renamed into place.
+fn new_directories(event: &Event) -> impl Iterator<Item = &PathBuf> {
+    use notify::event::ModifyKind;
+    use notify::EventKind;
+
+    let added = matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+    );
+    event
+        .paths
+        .iter()
+        .filter(move |path| added && path.is_dir())
+}
 
-        Ok(html_body)
+/// Sends watcher events to the watcher task without ever blocking the
+/// watcher's thread: adding a watch waits on that thread, so a bounded
+/// channel filled by the events `watch_tree` itself causes (listing a
+/// directory reports an open) would deadlock.
+fn forward_events(
+    tx: &mpsc::UnboundedSender<WatchResult>,
+) -> impl Fn(WatchResult) + Send + 'static {
+    let tx = tx.clone();
+    move |result| {
+        let _ = tx.send(result);
     }
 }
 
-/// Handles a markdown file that may have been created or modified.
-/// Refreshes tracked files or adds new files in directory mode, sending reload notifications.
-async fn handle_markdown_file_change(path: &Path, state: &SharedMarkdownState) {
-    if !is_markdown_file(path) {
-        return;
-    }
+fn is_watch_limit(error: &notify::Error) -> bool {
+    matches!(error.kind, notify::ErrorKind::MaxFilesWatch)
+}
 
-    let mut state_guard = state.lock().await;
+fn print_poll_fallback() {
+    println!(
+        "⚠️  OS file watch limit reached (on Linux, fs.inotify.max_user_watches); \
+         polling for changes every {}s instead. Raise the limit, or pass --poll=<MS> \
+         to pick the interval.",
+        DEFAULT_POLL_INTERVAL.as_secs()
+    );
+}
 
-    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-    let key = canonical
-        .strip_prefix(&state_guard.base_dir)
-        .unwrap_or(&canonical)
-        .to_string_lossy()
-        .to_string();
+/// Gathers the events following `first` until none arrive for `window`.
+async fn collect_burst<T>(
+    first: T,
+    rx: &mut mpsc::UnboundedReceiver<T>,
+    window: Duration,
+) -> Vec<T> {
+    let mut events = vec![first];
+    if window.is_zero() {
+        return events;
+    }
 
-    if state_guard.tracked_files.contains_key(&key) {
-        if state_guard.refresh_file(&key).is_ok() {
-            let _ = state_guard.change_tx.send(ServerMessage::Reload);
-        }
-    } else if state_guard.is_directory_mode {
-        if state_guard.add_tracked_file(canonical).is_ok() {
-            let _ = state_guard.change_tx.send(ServerMessage::Reload);
+    let cutoff = Instant::now() + window * MAX_BURST_WINDOWS;
+    loop {
+        let deadline = (Instant::now() + window).min(cutoff);
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(event)) => events.push(event),
+            Ok(None) | Err(_) => return events,
         }
     }
 }
 
-async fn handle_file_event(event: Event, state: &SharedMarkdownState) {
-    match event.kind {
-        notify::EventKind::Modify(notify::event::ModifyKind::Name(rename_mode)) => {
-            use notify::event::RenameMode;
-            match rename_mode {
-                RenameMode::Both => {
-                    // Linux/Windows: Both old and new paths provided in single event
-                    if event.paths.len() == 2 {
-                        let new_path = &event.paths[1];
-                        handle_markdown_file_change(new_path, state).await;
-                    }
-                }
-                RenameMode::From => {
-                    // File being renamed away - ignore
+/// Drops repeated events from a burst, keeping the first change of each
+/// path. Creates, content modifications, new write times (all the polling
+/// watcher reports) and renames into place all re-read the file as it is by
+/// the time the burst is processed, so a path is refreshed once however many
+/// of them name it. A rename whose new path was already refreshed is kept
+/// as a rename away of its old path.
+fn coalesce_events(events: Vec<Event>) -> Vec<Event> {
+    use notify::event::{MetadataKind, ModifyKind, RenameMode};
+    use notify::EventKind;
+
+    let mut refreshed = HashSet::new();
+    let mut seen = HashSet::new();
+    events
+        .into_iter()
+        .filter_map(|mut event| {
+            match event.kind {
+                // Only the old path is left to handle
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+                    if event.paths.len() == 2 && !refreshed.insert(event.paths[1].clone()) =>
+                {
+                    event.kind = EventKind::Modify(ModifyKind::Name(RenameMode::From));
+                    event.paths.truncate(1);
                 }
-                RenameMode::To => {
-                    // File renamed to this location
-                    if let Some(path) = event.paths.first() {
-                        handle_markdown_file_change(path, state).await;
-                    }
+                EventKind::Create(_)
+                | EventKind::Modify(ModifyKind::Data(_))
+                | EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime))
+                | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                    event.paths.retain(|path| refreshed.insert(path.clone()));
                 }
-                RenameMode::Any => {
-                    // macOS: Sends separate events for old and new paths
-                    // Use file existence to distinguish old (doesn't exist) from new (exists)
-                    if let Some(path) = event.paths.first() {
-                        if path.exists() {
-                            handle_markdown_file_change(path, state).await;
-                        }
-                    }
+                // Only the side that exists is the new path
+                EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => {
+                    event
+                        .paths
+                        .retain(|path| !path.exists() || refreshed.insert(path.clone()));
                 }
                 _ => {}
             }
-        }
-        _ => {
-            for path in &event.paths {
-                if is_markdown_file(path) {
-                    match event.kind {
-                        notify::EventKind::Create(_)
-                        | notify::EventKind::Modify(notify::event::ModifyKind::Data(_)) => {
-                            handle_markdown_file_change(path, state).await;
-                        }
-                        notify::EventKind::Remove(_) => {
-                            // Don't remove files from tracking. Editors like neovim save by
-                            // renaming the file to a backup, then creating a new one. If we
-                            // removed the file here, HTTP requests during that window would
-                            // see empty tracked_files and return 404.
-                        }
-                        _ => {}
-                    }
-                } else if path.is_file() && is_image_file(path.to_str().unwrap_or("")) {
-                    match event.kind {
-                        notify::EventKind::Modify(_)
-                        | notify::EventKind::Create(_)
-                        | notify::EventKind::Remove(_) => {
-                            let state_guard = state.lock().await;
-                            let _ = state_guard.change_tx.send(ServerMessage::Reload);
-                        }
-                        _ => {}
-                    }
-                }
-            }
-        }
-    }
+            (!event.paths.is_empty() && seen.insert((event.kind, event.paths.clone())))
+                .then_some(event)
+        })
+        .collect()
 }
 
 fn new_router(
     base_dir: PathBuf,
     tracked_files: Vec<PathBuf>,
     is_directory_mode: bool,
+    options: &ServeOptions,
 ) -> Result<Router> {
     let base_dir = base_dir.canonicalize()?;
 
-    let state = Arc::new(Mutex::new(MarkdownState::new(
+    let state = Arc::new(MarkdownState::new(
         base_dir.clone(),
         tracked_files,
         is_directory_mode,
-    )?));
+        options.render_cache,
+    )?);
+
+    if options.prewarm {
+        tokio::spawn(prewarm_renders(state.clone()));
+    }
 
     let watcher_state = state.clone();
-    let (tx, mut rx) = mpsc::channel(100);
+    let filter = options.filter.matcher(&base_dir)?;
+    let debounce = options.debounce;
+    let mut polling = options.poll.is_some();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = start_watcher(&base_dir, &tx, options.poll, &filter)?;
+    let watch_dir = base_dir.clone();
 
-    let mut watcher = RecommendedWatcher::new(
-        move |res: std::result::Result<Event, notify::Error>| {
-            if let Ok(event) = res {
-                let _ = tx.blocking_send(event);
+    tokio::spawn(async move {
+        // Dropping the watcher stops it, so the task keeps it alive
+        // Removals are reconciled once no burst has removed anything for the
+        // grace period, so a run of deletes or an editor save is handled once
+        let mut reconcile_at: Option<Instant> = None;
+        loop {
+            let event = match reconcile_at {
+                Some(deadline) => tokio::select! {
+                    event = rx.recv() => event,
+                    () = tokio::time::sleep_until(deadline) => {
+                        reconcile_at = None;
+                        reconcile_removed_files(&watcher_state).await;
+                        continue;
+                    }
+                },
+                None => rx.recv().await,
+            };
+            let Some(event) = event else {
+                break;
+            };
+
+            let mut events = Vec::new();
+            let mut watch_limit_reached = false;
+            for result in collect_burst(event, &mut rx, debounce).await {
+                match result {
+                    Ok(event) => events.push(event),
+                    Err(e) => watch_limit_reached |= is_watch_limit(&e),
+                }
             }
-        },
-        Config::default(),
-    )?;
 
-    watcher.watch(&base_dir, RecursiveMode::Recursive)?;
+            if events
+                .iter()
+                .any(|event| event.paths.iter().any(|path| is_ignore_file(path)))
+            {
+                filter.forget_ignore_files();
+            }
+            let recursive = RECURSIVE_WATCH && !polling;
+            if recursive {
+                events.retain(|event| {
+                    event.paths.is_empty()
+                        || !event
+                            .paths
+                            .iter()
+                            .all(|path| filter.is_in_ignored_dir(path))
+                });
+            }
+            // Watch new directories before their contents are scanned, so
+            // nothing added in between is missed
+            for dir in events.iter().flat_map(new_directories) {
+                if recursive || filter.is_ignored_dir(dir) {
+                    continue;
+                }
+                if let Err(e) = watch_tree(&mut *watcher, dir, &filter) {
+                    watch_limit_reached |= is_watch_limit(&e);
+                }
+            }
+            // New directories can exhaust the watch limit after startup
+            if watch_limit_reached && !polling {
+                print_poll_fallback();
+                match start_poll_watcher(&watch_dir, &tx, DEFAULT_POLL_INTERVAL, &filter) {
+                    Ok(poll_watcher) => {
+                        watcher = poll_watcher;
+                        polling = true;
+                    }
+                    Err(e) => eprintln!("Failed to start polling watcher: {e}"),
+                }
+            }
 
-    tokio::spawn(async move {
-        let _watcher = watcher;
-        while let Some(event) = rx.recv().await {
-            handle_file_event(event, &watcher_state).await;
+            // Taken before coalescing, which may merge the two halves of a
+            // rename into other events
+            watcher_state.record_moves(Moves::from_events(&events));
+            let mut removed = false;
+            for event in coalesce_events(events) {
+                removed |= handle_file_event(event, &watcher_state, &filter).await;
+            }
+            if removed {
+                reconcile_at = Some(Instant::now() + REMOVAL_GRACE_PERIOD);
+            }
         }
     });
 
     let router = Router::new()
         .route("/", get(serve_html_root))
-        .route("/ws", get(websocket_handler))
+        .route(
+            "/ws",
+            get(websocket_handler).layer(middleware::from_fn_with_state(
+                Arc::new(SocketOrigins {
+                    allowed_hosts: options.allowed_hosts.clone(),
+                    cors_origins: options.cors_origins.clone(),
+                }),
+                reject_cross_origin,
+            )),
+        )
         .route("/mermaid.min.js", get(serve_mermaid_js))
+        .route("/api/search", get(search_handler))
+        .route("/api/check", get(check_handler))
+        .route("/print/*filepath", get(serve_print))
         .route("/*filepath", get(serve_file))
-        .layer(CorsLayer::permissive())
         .with_state(state);
 
+    // Layers run outside in: the host check, CORS (so preflight requests
+    // don't need credentials), then authentication
+    let mut router = match &options.auth {
+        Some(auth) => router.layer(middleware::from_fn_with_state(
+            Arc::new(AuthState {
+                auth: auth.clone(),
+                https: options.tls.is_some(),
+            }),
+            require_auth,
+        )),
+        None => router,
+    };
+    // Without CORS headers browsers keep other origins from reading responses
+    if !options.cors_origins.is_empty() {
+        let origins = options
+            .cors_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).with_context(|| format!("invalid origin {origin:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        router = router.layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::list(origins))
+                .allow_methods([Method::GET, Method::HEAD]),
+        );
+    }
+    let router = router.layer(middleware::from_fn_with_state(
+        Arc::new(options.allowed_hosts.clone()),
+        validate_host,
+    ));
+
     Ok(router)
 }
 
+/// Renders tracked files in file order until the render cache is full, one
+/// at a time on the blocking thread pool so requests aren't held up.
+async fn prewarm_renders(state: SharedMarkdownState) {
+    let capacity = lock(&state.renders).cap().get();
+    for filename in state.get_sorted_filenames().into_iter().take(capacity) {
+        let state = state.clone();
+        let _ = tokio::task::spawn_blocking(move || state.refresh_file(&filename)).await;
+    }
+}
+
 pub(crate) async fn serve_markdown(
     base_dir: PathBuf,
     tracked_files: Vec<PathBuf>,
@@ -340,11 +2990,19 @@ pub(crate) async fn serve_markdown(
     hostname: impl AsRef<str>,
     port: u16,
     open: bool,
+    mut options: ServeOptions,
 ) -> Result<()> {
     let hostname = hostname.as_ref();
+    options.allowed_hosts.allow(hostname);
+    // Load the certificate before binding so a bad one fails fast
+    let tls_config = match &options.tls {
+        Some(tls) => Some(tls.config(hostname, &options.allowed_hosts).await?),
+        None => None,
+    };
+    let tls = tls_config.is_some();
 
     let first_file = tracked_files.first().cloned();
-    let router = new_router(base_dir.clone(), tracked_files, is_directory_mode)?;
+    let router = new_router(base_dir.clone(), tracked_files, is_directory_mode, &options)?;
 
     let (listener, actual_port) = bind_with_port_increment(hostname, port).await?;
 
@@ -353,6 +3011,7 @@ pub(crate) async fn serve_markdown(
     }
 
     let listen_addr = format_host(hostname, actual_port);
+    let listen_url = format_url(hostname, actual_port, tls);
 
     if is_directory_mode {
         println!("📁 Serving markdown files from: {}", base_dir.display());
@@ -360,20 +3019,255 @@ pub(crate) async fn serve_markdown(
         println!("📄 Serving markdown file: {}", file_path.display());
     }
 
-    println!("🌐 Server running at: http://{listen_addr}");
-    println!("⚡ Live reload enabled");
+    let start_path = options
+        .auth
+        .as_ref()
+        .map(Auth::start_path)
+        .unwrap_or_default();
+    println!("🌐 Server running at: {listen_url}{start_path}");
+    if let Some(Tls::SelfSigned) = options.tls {
+        println!("🔐 Using a self-signed certificate; browsers will ask you to accept it");
+    }
+    match &options.auth {
+        Some(Auth::Basic(_)) => println!("🔒 Basic authentication required"),
+        Some(Auth::Token(_)) => println!("🔒 Token required; it is part of the URL above"),
+        None if !is_loopback_host(hostname) => {
+            print_unauthenticated_warning(hostname, &listen_addr)
+        }
+        None => {}
+    }
+    match options.poll {
+        Some(interval) => println!(
+            "⚡ Live reload enabled (polling every {}ms)",
+            interval.as_millis()
+        ),
+        None => println!("⚡ Live reload enabled"),
+    }
     println!("\nPress Ctrl+C to stop the server");
 
     if open {
-        let browse_addr = format_host(&browsable_host(hostname), actual_port);
-        open_browser(&format!("http://{browse_addr}"))?;
+        let browse_url = format_url(&browsable_host(hostname), actual_port, tls);
+        open_browser(&format!("{browse_url}{start_path}"))?;
     }
 
-    axum::serve(listener, router).await?;
+    serve_router(listener, router, tls_config).await
+}
+
+/// Serves `router` on `listener` until the server stops, over HTTPS when
+/// there is a TLS configuration.
+async fn serve_router(
+    listener: TcpListener,
+    router: Router,
+    tls_config: Option<RustlsConfig>,
+) -> Result<()> {
+    match tls_config {
+        Some(config) => {
+            axum_server::from_tcp_rustls(listener.into_std()?, config)
+                .serve(router.into_make_service())
+                .await?
+        }
+        None => axum::serve(listener, router).await?,
+    }
 
     Ok(())
 }
 
+/// Checks the links and images of every file, as the `check` subcommand.
+pub(crate) fn check_links(
+    base_dir: PathBuf,
+    tracked_files: Vec<PathBuf>,
+    is_directory_mode: bool,
+) -> Result<Vec<CheckIssue>> {
+    let base_dir = base_dir.canonicalize()?;
+    let state = MarkdownState::new(
+        base_dir,
+        tracked_files,
+        is_directory_mode,
+        DEFAULT_RENDER_CACHE,
+    )?;
+    Ok(state.check())
+}
+
+/// Renders every tracked file to `<output_dir>/<key>.html` without starting a
+/// server or watcher. Links between documents are rewritten to the exported
+/// pages, and the images they embed are copied along, as is the Mermaid
+/// script when a page needs it. Returns the number of pages written.
+pub(crate) fn export_site(
+    base_dir: PathBuf,
+    tracked_files: Vec<PathBuf>,
+    is_directory_mode: bool,
+    output_dir: &Path,
+) -> Result<usize> {
+    let base_dir = base_dir.canonicalize()?;
+    let state = MarkdownState::new(
+        base_dir.clone(),
+        tracked_files,
+        is_directory_mode,
+        DEFAULT_RENDER_CACHE,
+    )?;
+    let filenames = state.get_sorted_filenames();
+
+    let mut images = BTreeSet::new();
+    let mut needs_mermaid = false;
+    for filename in &filenames {
+        state.refresh_file(filename)?;
+        let (status, Html(page)) = render_markdown(&state, filename, PageTarget::Static);
+        if status != StatusCode::OK {
+            anyhow::bail!("Failed to render {filename}: {page}");
+        }
+
+        let render = state.cached_render(filename);
+        let html = render.as_ref().map_or("", |render| render.html.as_str());
+        images.extend(referenced_images(filename, html));
+        needs_mermaid |= html.contains(r#"class="language-mermaid""#);
+
+        let root = "../".repeat(filename.matches('/').count());
+        let page = rewrite_links_for_export(&page, &root);
+        write_export_file(output_dir, &export_page_path(filename), page.as_bytes())?;
+    }
+
+    for image in &images {
+        match read_base_dir_file(&base_dir, image) {
+            Ok(contents) => write_export_file(output_dir, image, &contents)?,
+            Err(StatusCode::FORBIDDEN) => {
+                eprintln!("Skipping image outside {}: {image}", base_dir.display())
+            }
+            Err(_) => eprintln!("Skipping missing image: {image}"),
+        }
+    }
+
+    if needs_mermaid {
+        write_export_file(output_dir, "mermaid.min.js", MERMAID_JS.as_bytes())?;
+    }
+
+    // `/` serves the first file; mirror that unless a page is already the index
+    let pages: Vec<String> = filenames.iter().map(|f| export_page_path(f)).collect();
+    if let Some(first) = pages
+        .first()
+        .filter(|_| !pages.iter().any(|p| p == "index.html"))
+    {
+        let first = escape_html(first);
+        let redirect = format!(
+            "<!DOCTYPE html>\n<meta charset=\"utf-8\">\n<meta http-equiv=\"refresh\" content=\"0; url={first}\">\n<a href=\"{first}\">{first}</a>\n"
+        );
+        write_export_file(output_dir, "index.html", redirect.as_bytes())?;
+    }
+
+    Ok(filenames.len())
+}
+
+/// Renders one markdown file to a single self-contained HTML file: images
+/// are embedded as data URIs and the Mermaid script is inlined, so the page
+/// opens offline with no server.
+pub(crate) fn export_single_file(base_dir: PathBuf, file: PathBuf, output: &Path) -> Result<()> {
+    let base_dir = base_dir.canonicalize()?;
+    let state = MarkdownState::new(base_dir.clone(), vec![file], false, DEFAULT_RENDER_CACHE)?;
+    let filename = state
+        .get_sorted_filenames()
+        .into_iter()
+        .next()
+        .context("No markdown file to export")?;
+    state.refresh_file(&filename)?;
+
+    let (status, Html(page)) = render_markdown(&state, &filename, PageTarget::SelfContained);
+    if status != StatusCode::OK {
+        anyhow::bail!("Failed to render {filename}: {page}");
+    }
+
+    let page = rewrite_attributes(&page, &["src=\""], |src| {
+        image_data_uri(&base_dir, &filename, src).unwrap_or_else(|| src.to_string())
+    });
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(output, page).with_context(|| format!("Failed to write {}", output.display()))
+}
+
+/// Reads the image behind `src` on `page` and encodes it as a data URI.
+fn image_data_uri(base_dir: &Path, page: &str, src: &str) -> Option<String> {
+    let path = resolve_image_src(page, src)?;
+    match read_base_dir_file(base_dir, &path) {
+        Ok(contents) => Some(format!(
+            "data:{};base64,{}",
+            guess_image_content_type(&path),
+            BASE64_STANDARD.encode(contents)
+        )),
+        Err(_) => {
+            eprintln!("Skipping unreadable image: {path}");
+            None
+        }
+    }
+}
+
+fn write_export_file(output_dir: &Path, relative: &str, contents: &[u8]) -> Result<()> {
+    let path = output_dir.join(relative);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Output path of a tracked file's page, e.g. `guides/setup.html`.
+fn export_page_path(key: &str) -> String {
+    Path::new(key)
+        .with_extension("html")
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Points every `href`/`src` of an exported page at the exported files:
+/// markdown links become `.html` links and server-absolute paths become
+/// relative to `root`, the way back from the page to the site root.
+fn rewrite_links_for_export(html: &str, root: &str) -> String {
+    rewrite_attributes(html, &["href=\"", "src=\""], |link| export_link(link, root))
+}
+
+/// Replaces the value of every double-quoted occurrence of the given
+/// attributes (passed with their `="`, e.g. `src="`).
+fn rewrite_attributes(
+    html: &str,
+    attributes: &[&str],
+    mut rewrite: impl FnMut(&str) -> String,
+) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = attributes
+        .iter()
+        .filter_map(|attribute| rest.find(attribute).map(|pos| pos + attribute.len()))
+        .min()
+    {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find('"').unwrap_or(rest.len());
+        output.push_str(&rewrite(&rest[..end]));
+        rest = &rest[end..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+fn export_link(link: &str, root: &str) -> String {
+    // Fragments, protocol-relative and scheme URLs (`https:`, `mailto:`, `data:`)
+    if link.is_empty() || link.starts_with('#') || link.starts_with("//") || link.contains(':') {
+        return link.to_string();
+    }
+
+    let (path, suffix) = link.split_at(link.find(['#', '?']).unwrap_or(link.len()));
+    let path = match path.strip_prefix('/') {
+        Some("") => format!("{root}index.html"),
+        Some(absolute) => format!("{root}{absolute}"),
+        None => path.to_string(),
+    };
+    if is_markdown_file(Path::new(&path)) {
+        format!("{}{suffix}", export_page_path(&path))
+    } else {
+        format!("{path}{suffix}")
+    }
+}
+
 const MAX_PORT_ATTEMPTS: u16 = 100;
 
 async fn bind_with_port_increment(hostname: &str, start_port: u16) -> Result<(TcpListener, u16)> {
@@ -404,6 +3298,31 @@ fn format_host(hostname: &str, port: u16) -> String {
     }
 }
 
+/// Format the URL of the server root, `https://` when serving TLS.
+fn format_url(hostname: &str, port: u16, tls: bool) -> String {
+    let scheme = if tls { "https" } else { "http" };
+    format!("{scheme}://{}", format_host(hostname, port))
+}
+
+/// Whether `hostname` only accepts connections from this machine.
+fn is_loopback_host(hostname: &str) -> bool {
+    hostname.eq_ignore_ascii_case("localhost")
+        || hostname.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn print_unauthenticated_warning(hostname: &str, listen_addr: &str) {
+    let reach = if browsable_host(hostname) != hostname {
+        "every network interface"
+    } else {
+        "the network"
+    };
+    println!(
+        "\n⚠️  WARNING: serving on {reach} ({listen_addr}) without authentication.\n\
+         ⚠️  Anyone who can reach this address can read every file under the served directory.\n\
+         ⚠️  Pass --token or --auth USER:PASSWORD to require credentials.\n"
+    );
+}
+
 /// Map wildcard bind addresses to loopback so the browser gets a
 /// reachable URL.
 fn browsable_host(hostname: &str) -> String {
@@ -459,8 +3378,6 @@ fn open_browser(url: &str) -> Result<()> {
 }
 
 async fn serve_html_root(State(state): State<SharedMarkdownState>) -> impl IntoResponse {
-    let mut state = state.lock().await;
-
     let filename = match state.get_sorted_filenames().into_iter().next() {
         Some(name) => name,
         None => {
@@ -471,38 +3388,110 @@ async fn serve_html_root(State(state): State<SharedMarkdownState>) -> impl IntoR
         }
     };
 
-    let _ = state.refresh_file(&filename);
-
-    render_markdown(&state, &filename).await
+    render_page(state, filename, PageTarget::Live).await
 }
 
 async fn serve_file(
     AxumPath(filepath): AxumPath<String>,
     State(state): State<SharedMarkdownState>,
+    request: Request,
 ) -> axum::response::Response {
     if filepath.ends_with(".md") || filepath.ends_with(".markdown") {
-        let mut state = state.lock().await;
-
-        if !state.tracked_files.contains_key(&filepath) {
-            return (StatusCode::NOT_FOUND, Html("File not found".to_string())).into_response();
+        if !read_lock(&state.tracked_files).contains_key(&filepath) {
+            return renamed_redirect(&state, "/", &filepath, &request).unwrap_or_else(|| {
+                (StatusCode::NOT_FOUND, Html("File not found".to_string())).into_response()
+            });
         }
 
-        let _ = state.refresh_file(&filepath);
-
-        let (status, html) = render_markdown(&state, &filepath).await;
-        (status, html).into_response()
-    } else if is_image_file(&filepath) {
-        serve_static_file_inner(filepath, state).await
+        render_page(state, filepath, PageTarget::Live)
+            .await
+            .into_response()
+    } else if static_content_type(&filepath).is_some() {
+        serve_static_file_inner(filepath, state, request).await
     } else {
         (StatusCode::NOT_FOUND, Html("File not found".to_string())).into_response()
     }
 }
 
-fn build_file_tree(paths: &[String]) -> Vec<Value> {
-    build_tree_level(paths, "")
+/// Redirects a request for the old key of a renamed file to its current key
+/// under `route`, keeping the query string; `None` if it wasn't renamed.
+fn renamed_redirect(
+    state: &MarkdownState,
+    route: &str,
+    filepath: &str,
+    request: &Request,
+) -> Option<axum::response::Response> {
+    let new_key = read_lock(&state.redirects).get(filepath).cloned()?;
+    // Keep `?toc=1` and the like
+    let mut location = format!("{route}{}", utf8_percent_encode(&new_key, URL_PATH));
+    if let Some(query) = request.uri().query() {
+        location = format!("{location}?{query}");
+    }
+    Some(Redirect::temporary(&location).into_response())
+}
+
+#[derive(Deserialize)]
+struct PrintParams {
+    toc: Option<String>,
+}
+
+/// Serves the print layout of a tracked markdown file, with a table of
+/// contents when `?toc` is set.
+///
+/// `/print/` would shadow a `print/` directory under the base directory, so
+/// files tracked there, and other paths that exist there, are served as
+/// usual. Any other path is looked up without the prefix, so the relative
+/// images of a print page still load.
+async fn serve_print(
+    AxumPath(filepath): AxumPath<String>,
+    Query(params): Query<PrintParams>,
+    State(state): State<SharedMarkdownState>,
+    request: Request,
+) -> axum::response::Response {
+    let shadowed = format!("print/{filepath}");
+    let is_tracked = |key: &str| read_lock(&state.tracked_files).contains_key(key);
+    if !(filepath.ends_with(".md") || filepath.ends_with(".markdown")) {
+        let path = if state.base_dir.join(&shadowed).exists() {
+            shadowed
+        } else {
+            filepath
+        };
+        return serve_file(AxumPath(path), State(state), request).await;
+    }
+    if is_tracked(&shadowed) {
+        return serve_file(AxumPath(shadowed), State(state), request).await;
+    }
+    if !is_tracked(&filepath) {
+        return match renamed_redirect(&state, "/print/", &filepath, &request) {
+            Some(redirect) => redirect,
+            // Possibly a file renamed away from under `print/`
+            None => serve_file(AxumPath(shadowed), State(state), request).await,
+        };
+    }
+
+    let target = PageTarget::Print {
+        toc: is_set(params.toc),
+    };
+    render_page(state, filepath, target).await.into_response()
+}
+
+/// Whether a query flag is present and not `0` or `false`; a bare `?toc`
+/// counts as set.
+fn is_set(flag: Option<String>) -> bool {
+    flag.is_some_and(|flag| !matches!(flag.as_str(), "0" | "false"))
+}
+
+/// Builds the sidebar tree. Files are labelled with their frontmatter title
+/// from `titles` when they have one.
+fn build_file_tree(paths: &[String], titles: &HashMap<String, String>) -> Vec<Value> {
+    build_tree_level(paths, "", titles)
 }
 
-fn build_tree_level(paths: &[String], prefix: &str) -> Vec<Value> {
+fn build_tree_level(
+    paths: &[String],
+    prefix: &str,
+    titles: &HashMap<String, String>,
+) -> Vec<Value> {
     let mut dirs: BTreeMap<String, Vec<String>> = BTreeMap::new();
     let mut files: Vec<String> = Vec::new();
 
@@ -526,7 +3515,7 @@ fn build_tree_level(paths: &[String], prefix: &str) -> Vec<Value> {
         } else {
             format!("{}/{}", prefix, dir_name)
         };
-        let children = build_tree_level(sub_paths, &dir_prefix);
+        let children = build_tree_level(sub_paths, &dir_prefix, titles);
         let mut map = HashMap::new();
         map.insert("name".to_string(), Value::from(dir_name.clone()));
         map.insert("is_dir".to_string(), Value::from(true));
@@ -542,6 +3531,9 @@ fn build_tree_level(paths: &[String], prefix: &str) -> Vec<Value> {
         };
         let mut map = HashMap::new();
         map.insert("name".to_string(), Value::from(file_name.clone()));
+        if let Some(title) = titles.get(&full_path) {
+            map.insert("title".to_string(), Value::from(title.clone()));
+        }
         map.insert("path".to_string(), Value::from(full_path));
         map.insert("is_dir".to_string(), Value::from(false));
         items.push((file_name.to_lowercase(), Value::from_object(map)));
@@ -551,7 +3543,46 @@ fn build_tree_level(paths: &[String], prefix: &str) -> Vec<Value> {
     items.into_iter().map(|(_, v)| v).collect()
 }
 
-async fn render_markdown(state: &MarkdownState, current_file: &str) -> (StatusCode, Html<String>) {
+/// Where a rendered page is going to be viewed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PageTarget {
+    /// Served by mdserve, with live reload and the server-backed controls.
+    Live,
+    /// Written to disk by an export, with no server behind it.
+    Static,
+    /// Written as one standalone file, with scripts inlined.
+    SelfContained,
+    /// Served for printing: no chrome, a cover page and optionally a table of
+    /// contents in front of the document.
+    Print { toc: bool },
+}
+
+/// Refreshes `filename` and builds its page on the blocking thread pool, so
+/// reading and rendering a large file doesn't hold up other requests on the
+/// same runtime thread.
+async fn render_page(
+    state: SharedMarkdownState,
+    filename: String,
+    target: PageTarget,
+) -> (StatusCode, Html<String>) {
+    tokio::task::spawn_blocking(move || {
+        let _ = state.refresh_file(&filename);
+        render_markdown(&state, &filename, target)
+    })
+    .await
+    .unwrap_or_else(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Html(format!("Rendering error: {e}")),
+        )
+    })
+}
+
+fn render_markdown(
+    state: &MarkdownState,
+    current_file: &str,
+    target: PageTarget,
+) -> (StatusCode, Html<String>) {
     let env = template_env();
     let template = match env.get_template(TEMPLATE_NAME) {
         Ok(t) => t,
@@ -563,24 +3594,38 @@ async fn render_markdown(state: &MarkdownState, current_file: &str) -> (StatusCo
         }
     };
 
-    let (content, has_mermaid) = if let Some(tracked) = state.tracked_files.get(current_file) {
-        let html = &tracked.html;
-        let mermaid = html.contains(r#"class="language-mermaid""#);
-        (Value::from_safe_string(html.clone()), mermaid)
-    } else {
+    let frontmatter = read_lock(&state.tracked_files)
+        .get(current_file)
+        .map(|tracked| tracked.frontmatter.clone());
+    // Callers refresh the file first, so a missing render means it couldn't be read
+    let (Some(frontmatter), Some(render)) = (frontmatter, state.cached_render(current_file)) else {
         return (StatusCode::NOT_FOUND, Html("File not found".to_string()));
     };
-
-    let rendered = if state.show_navigation() {
+    let content = Value::from_safe_string(state.resolve_links(current_file, &render.html));
+    let has_mermaid = render.html.contains(r#"class="language-mermaid""#);
+    let title = frontmatter.title.clone();
+    let headings = Value::from_serialize(&render.headings);
+    let mermaid_js = (target == PageTarget::SelfContained && has_mermaid)
+        .then(|| Value::from_safe_string(escape_inline_script(MERMAID_JS)));
+    let print = matches!(target, PageTarget::Print { .. });
+    let print_toc = matches!(target, PageTarget::Print { toc: true });
+    let cover = (print && frontmatter != Frontmatter::default())
+        .then(|| Value::from_serialize(&frontmatter));
+
+    let rendered = if state.show_navigation() && !print {
         let filenames = state.get_sorted_filenames();
-        let tree = build_file_tree(&filenames);
+        let tree = build_file_tree(&filenames, &state.titles());
 
         match template.render(context! {
             content => content,
+            title => title,
+            headings => headings,
             mermaid_enabled => has_mermaid,
             show_navigation => true,
             tree => tree,
             current_file => current_file,
+            live => target == PageTarget::Live,
+            mermaid_js => mermaid_js,
         }) {
             Ok(r) => r,
             Err(e) => {
@@ -593,8 +3638,16 @@ async fn render_markdown(state: &MarkdownState, current_file: &str) -> (StatusCo
     } else {
         match template.render(context! {
             content => content,
+            title => title,
+            headings => headings,
             mermaid_enabled => has_mermaid,
             show_navigation => false,
+            current_file => current_file,
+            live => target == PageTarget::Live,
+            mermaid_js => mermaid_js,
+            print => print,
+            print_toc => print_toc,
+            cover => cover,
         }) {
             Ok(r) => r,
             Err(e) => {
@@ -609,6 +3662,20 @@ async fn render_markdown(state: &MarkdownState, current_file: &str) -> (StatusCo
     (StatusCode::OK, Html(rendered))
 }
 
+async fn search_handler(
+    Query(params): Query<SearchParams>,
+    State(state): State<SharedMarkdownState>,
+) -> Json<Vec<SearchHit>> {
+    // Indexes files that haven't been rendered yet, reading each of them
+    let hits = tokio::task::spawn_blocking(move || state.search(&params.q)).await;
+    Json(hits.unwrap_or_default())
+}
+
+async fn check_handler(State(state): State<SharedMarkdownState>) -> Json<Vec<CheckIssue>> {
+    let issues = tokio::task::spawn_blocking(move || state.check()).await;
+    Json(issues.unwrap_or_default())
+}
+
 async fn serve_mermaid_js(headers: HeaderMap) -> impl IntoResponse {
     if is_etag_match(&headers) {
         return mermaid_response(StatusCode::NOT_MODIFIED, None);
@@ -643,42 +3710,35 @@ fn mermaid_response(status: StatusCode, body: Option<&'static str>) -> impl Into
 async fn serve_static_file_inner(
     filename: String,
     state: SharedMarkdownState,
+    request: Request,
 ) -> axum::response::Response {
-    let state = state.lock().await;
-
-    let full_path = state.base_dir.join(&filename);
-
-    match full_path.canonicalize() {
-        Ok(canonical_path) => {
-            if !canonical_path.starts_with(&state.base_dir) {
-                return (
-                    StatusCode::FORBIDDEN,
-                    [(header::CONTENT_TYPE, "text/plain")],
-                    "Access denied".to_string(),
-                )
-                    .into_response();
-            }
-
-            match fs::read(&canonical_path) {
-                Ok(contents) => {
-                    let content_type = guess_image_content_type(&filename);
-                    (
-                        StatusCode::OK,
-                        [(header::CONTENT_TYPE, content_type.as_str())],
-                        contents,
-                    )
-                        .into_response()
+    let content_type = static_content_type(&filename).unwrap_or("application/octet-stream");
+
+    match base_dir_path(&state.base_dir, &filename) {
+        Ok(path) => {
+            // `ServeFile` answers Range (for seeking in video and audio),
+            // conditional and HEAD requests
+            let Ok(mut response) = ServeFile::new(path).oneshot(request).await;
+            if response.status().is_success() {
+                let headers = response.headers_mut();
+                headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+                if is_active_content(content_type) {
+                    headers.insert(
+                        header::CONTENT_SECURITY_POLICY,
+                        HeaderValue::from_static("sandbox"),
+                    );
                 }
-                Err(_) => (
-                    StatusCode::NOT_FOUND,
-                    [(header::CONTENT_TYPE, "text/plain")],
-                    "File not found".to_string(),
-                )
-                    .into_response(),
             }
+            response.into_response()
         }
-        Err(_) => (
-            StatusCode::NOT_FOUND,
+        Err(StatusCode::FORBIDDEN) => (
+            StatusCode::FORBIDDEN,
+            [(header::CONTENT_TYPE, "text/plain")],
+            "Access denied".to_string(),
+        )
+            .into_response(),
+        Err(status) => (
+            status,
             [(header::CONTENT_TYPE, "text/plain")],
             "File not found".to_string(),
         )
@@ -686,51 +3746,122 @@ async fn serve_static_file_inner(
     }
 }
 
+/// Reads a file under `base_dir`. Paths resolving outside it (through `..`
+/// or symlinks) are `FORBIDDEN`; missing or unreadable files `NOT_FOUND`.
+fn read_base_dir_file(base_dir: &Path, filename: &str) -> Result<Vec<u8>, StatusCode> {
+    let canonical_path = base_dir_path(base_dir, filename)?;
+    fs::read(&canonical_path).map_err(|_| StatusCode::NOT_FOUND)
+}
+
+/// Resolves an existing path under `base_dir`, with the same errors as
+/// `read_base_dir_file`.
+fn base_dir_path(base_dir: &Path, filename: &str) -> Result<PathBuf, StatusCode> {
+    let canonical_path = base_dir
+        .join(filename)
+        .canonicalize()
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if !canonical_path.starts_with(base_dir) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(canonical_path)
+}
+
 fn is_image_file(file_path: &str) -> bool {
+    static_content_type(file_path).is_some_and(|content_type| content_type.starts_with("image/"))
+}
+
+/// Content types of the files served next to the documents: images, video,
+/// audio, PDFs, tables and HTML attachments. Other files (source, config,
+/// plain text) are never served.
+const STATIC_CONTENT_TYPES: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("webp", "image/webp"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/x-icon"),
+    ("pdf", "application/pdf"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("tsv", "text/tab-separated-values; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+];
+
+/// Looks up the content type of a static asset, or `None` when files with
+/// this extension aren't served.
+fn static_content_type(file_path: &str) -> Option<&'static str> {
     let extension = std::path::Path::new(file_path)
         .extension()
-        .and_then(|ext| ext.to_str())
-        .unwrap_or("");
+        .and_then(|ext| ext.to_str())?
+        .to_lowercase();
 
-    matches!(
-        extension.to_lowercase().as_str(),
-        "png" | "jpg" | "jpeg" | "gif" | "svg" | "webp" | "bmp" | "ico"
-    )
+    STATIC_CONTENT_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map(|(_, content_type)| *content_type)
 }
 
 fn guess_image_content_type(file_path: &str) -> String {
-    let extension = std::path::Path::new(file_path)
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .unwrap_or("");
+    static_content_type(file_path)
+        .filter(|content_type| content_type.starts_with("image/"))
+        .unwrap_or("application/octet-stream")
+        .to_string()
+}
 
-    match extension.to_lowercase().as_str() {
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "gif" => "image/gif",
-        "svg" => "image/svg+xml",
-        "webp" => "image/webp",
-        "bmp" => "image/bmp",
-        "ico" => "image/x-icon",
-        _ => "application/octet-stream",
-    }
-    .to_string()
+/// Whether a browser opening content of this type would run its scripts.
+/// Served from mdserve's origin, they could read every document (and send
+/// the auth cookie), so such files are sandboxed into an origin of their own.
+fn is_active_content(content_type: &str) -> bool {
+    content_type.starts_with("text/html") || content_type == "image/svg+xml"
 }
 
+/// Accepts WebSocket connections from mdserve's own pages only. Browsers
+/// don't apply CORS to WebSockets, so a page on any other origin could
+/// otherwise subscribe to live updates.
 async fn websocket_handler(
     ws: WebSocketUpgrade,
+    Query(params): Query<WebSocketParams>,
     State(state): State<SharedMarkdownState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_websocket(socket, state))
+) -> axum::response::Response {
+    ws.on_upgrade(move |socket| handle_websocket(socket, state, params.file))
+        .into_response()
 }
 
-async fn handle_websocket(socket: WebSocket, state: SharedMarkdownState) {
+/// Forwards change notifications to a browser tab.
+///
+/// When the socket was opened for a specific page, only changes to that
+/// page's document or the images it embeds are forwarded, with document
+/// edits sent as content updates. Sockets without a page reload on every
+/// change.
+///
+/// The client may ask for its content annotated with changes at any time;
+/// the current annotated render is sent straight away and used for later
+/// updates.
+async fn handle_websocket(socket: WebSocket, state: SharedMarkdownState, page: Option<String>) {
     let (mut sender, mut receiver) = socket.split();
 
-    let mut change_rx = {
-        let state = state.lock().await;
-        state.change_tx.subscribe()
-    };
+    // Later edits are diffed against the render the page is showing
+    if let Some(page) = page.clone() {
+        let state = state.clone();
+        let _ = tokio::task::spawn_blocking(move || state.refresh_file(&page)).await;
+    }
+    let mut change_rx = state.change_tx.subscribe();
+
+    let (mode_tx, mut mode_rx) = watch::channel(ChangesMode::Off);
 
     let recv_task = tokio::spawn(async move {
         while let Some(msg) = receiver.next().await {
@@ -739,6 +3870,9 @@ async fn handle_websocket(socket: WebSocket, state: SharedMarkdownState) {
                     if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
                         match client_msg {
                             ClientMessage::Ping | ClientMessage::RequestRefresh => {}
+                            ClientMessage::ShowChanges { since } => {
+                                let _ = mode_tx.send(since);
+                            }
                         }
                     }
                 }
@@ -749,8 +3883,42 @@ async fn handle_websocket(socket: WebSocket, state: SharedMarkdownState) {
     });
 
     let send_task = tokio::spawn(async move {
-        while let Ok(reload_msg) = change_rx.recv().await {
-            if let Ok(json) = serde_json::to_string(&reload_msg) {
+        loop {
+            let outgoing = tokio::select! {
+                change = change_rx.recv() => {
+                    let Ok(change) = change else { break };
+                    let mode = *mode_rx.borrow();
+                    match page.clone() {
+                        Some(page) => {
+                            blocking_message(&state, move |state| {
+                                state.message_for_page(&change, &page, mode)
+                            })
+                            .await
+                        }
+                        None => Some(ServerMessage::Reload),
+                    }
+                }
+                changed = mode_rx.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let mode = *mode_rx.borrow_and_update();
+                    match page.clone() {
+                        Some(page) => {
+                            blocking_message(&state, move |state| {
+                                state.page_update(&page, mode, None)
+                            })
+                            .await
+                        }
+                        None => None,
+                    }
+                }
+            };
+            let Some(outgoing) = outgoing else {
+                continue;
+            };
+
+            if let Ok(json) = serde_json::to_string(&outgoing) {
                 if sender.send(Message::Text(json)).await.is_err() {
                     break;
                 }
@@ -764,6 +3932,19 @@ async fn handle_websocket(socket: WebSocket, state: SharedMarkdownState) {
     }
 }
 
+/// Builds a socket's next message on the blocking thread pool, since it may
+/// render the page and annotate its changes.
+async fn blocking_message(
+    state: &SharedMarkdownState,
+    build: impl FnOnce(&MarkdownState) -> Option<ServerMessage> + Send + 'static,
+) -> Option<ServerMessage> {
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || build(&state))
+        .await
+        .ok()
+        .flatten()
+}
+
 #[cfg(test)]
 #[path = "app_tests.rs"]
 mod tests;
@@ -71,7 +71,8 @@ fn test_guess_image_content_type() {
 fn test_scan_markdown_files_empty_directory() {
     let temp_dir = tempdir().expect("Failed to create temp dir");
 
-    let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+    let result =
+        scan_markdown_files(temp_dir.path(), &FileFilter::default()).expect("Failed to scan");
     assert_eq!(result.len(), 0);
 }
 
@@ -86,7 +87,8 @@ fn test_scan_markdown_files_with_markdown_files() {
     fs::write(temp_dir.path().join("test.txt"), "text").expect("Failed to write");
     fs::write(temp_dir.path().join("README"), "readme").expect("Failed to write");
 
-    let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+    let result =
+        scan_markdown_files(temp_dir.path(), &FileFilter::default()).expect("Failed to scan");
 
     assert_eq!(result.len(), 3);
 
@@ -107,7 +109,8 @@ fn test_scan_markdown_files_includes_subdirectories() {
     fs::create_dir(&sub_dir).expect("Failed to create subdir");
     fs::write(sub_dir.join("nested.md"), "# Nested").expect("Failed to write");
 
-    let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+    let result =
+        scan_markdown_files(temp_dir.path(), &FileFilter::default()).expect("Failed to scan");
 
     assert_eq!(result.len(), 2);
     let filenames: Vec<_> = result
@@ -118,6 +121,241 @@ fn test_scan_markdown_files_includes_subdirectories() {
     assert!(filenames.contains(&"nested.md"));
 }
 
+/// Lays out a docs tree with `.gitignore`/`.ignore` files and junk directories.
+fn create_ignore_tree() -> TempDir {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let root = temp_dir.path();
+    for dir in [
+        ".git",
+        "node_modules/pkg",
+        "target",
+        "guides",
+        "drafts",
+        "vendor",
+    ] {
+        fs::create_dir_all(root.join(dir)).expect("Failed to create dir");
+    }
+    for file in [
+        "README.md",
+        ".git/notes.md",
+        "node_modules/pkg/README.md",
+        "target/out.md",
+        "guides/setup.md",
+        "guides/scratch.md",
+        "drafts/idea.md",
+        "vendor/LICENSE.md",
+        "vendor/keep.md",
+    ] {
+        fs::write(root.join(file), "# Doc").expect("Failed to write");
+    }
+    fs::write(
+        root.join(".gitignore"),
+        "node_modules/\ntarget\nvendor/*\n!vendor/keep.md\n",
+    )
+    .expect("Failed to write .gitignore");
+    fs::write(root.join("guides/.ignore"), "scratch.md\n").expect("Failed to write .ignore");
+    temp_dir
+}
+
+fn scanned(dir: &Path, filter: &FileFilter) -> Vec<String> {
+    scan_markdown_files(dir, filter)
+        .expect("Failed to scan")
+        .iter()
+        .map(|path| {
+            path.strip_prefix(dir)
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn test_scan_markdown_files_honours_ignore_rules() {
+    let temp_dir = create_ignore_tree();
+    let root = temp_dir.path();
+
+    assert_eq!(
+        scanned(root, &FileFilter::default()),
+        vec![
+            "README.md",
+            "drafts/idea.md",
+            "guides/setup.md",
+            "vendor/keep.md"
+        ]
+    );
+
+    let filter = FileFilter::new(vec![], vec!["drafts".to_string()], true).unwrap();
+    assert_eq!(
+        scanned(root, &filter),
+        vec!["README.md", "guides/setup.md", "vendor/keep.md"]
+    );
+
+    let filter = FileFilter::new(vec!["guides/**".to_string()], vec![], true).unwrap();
+    assert_eq!(scanned(root, &filter), vec!["guides/setup.md"]);
+
+    // `.git` stays out even without ignore files
+    let filter = FileFilter::new(vec![], vec![], false).unwrap();
+    assert_eq!(scanned(root, &filter).len(), 8);
+    assert!(!scanned(root, &filter).contains(&".git/notes.md".to_string()));
+
+    assert!(FileFilter::new(vec![], vec!["[".to_string()], true).is_err());
+}
+
+#[test]
+fn test_file_filter_matches_scan_for_new_files() {
+    let temp_dir = create_ignore_tree();
+    let root = temp_dir.path();
+    let filter = FileFilter::new(vec![], vec!["drafts".to_string()], true)
+        .unwrap()
+        .matcher(root)
+        .unwrap();
+
+    for (file, ignored) in [
+        ("README.md", false),
+        ("guides/setup.md", false),
+        ("guides/scratch.md", true),
+        ("node_modules/pkg/README.md", true),
+        ("target/out.md", true),
+        ("vendor/LICENSE.md", true),
+        ("vendor/keep.md", false),
+        ("drafts/idea.md", true),
+        (".git/notes.md", true),
+    ] {
+        assert_eq!(filter.is_ignored(&root.join(file)), ignored, "{file}");
+    }
+
+    // `vendor/*` ignores what is in the directory, not the directory itself
+    for (dir, ignored) in [
+        ("guides", false),
+        ("vendor", false),
+        ("node_modules", true),
+        ("node_modules/pkg", true),
+        ("target", true),
+        ("drafts", true),
+        (".git", true),
+    ] {
+        assert_eq!(filter.is_ignored_dir(&root.join(dir)), ignored, "{dir}");
+    }
+
+    // What a recursive watch reports from inside ignored directories
+    for (path, ignored) in [
+        ("README.md", false),
+        ("guides/scratch.md", false),
+        ("node_modules/pkg/index.js", true),
+        (".git/index", true),
+        ("target", false),
+    ] {
+        assert_eq!(
+            filter.is_in_ignored_dir(&root.join(path)),
+            ignored,
+            "{path}"
+        );
+    }
+
+    // Ignore files are re-read once the watcher reports a change to one
+    fs::write(root.join("guides/.ignore"), "setup.md\n").expect("Failed to write .ignore");
+    assert!(!filter.is_ignored(&root.join("guides/setup.md")));
+    filter.forget_ignore_files();
+    assert!(filter.is_ignored(&root.join("guides/setup.md")));
+    assert!(!filter.is_ignored(&root.join("guides/scratch.md")));
+}
+
+/// Records the paths it is asked to watch.
+struct RecordingWatcher(Vec<PathBuf>);
+
+impl Watcher for RecordingWatcher {
+    fn new<F: notify::EventHandler>(_: F, _: Config) -> notify::Result<Self> {
+        Ok(RecordingWatcher(Vec::new()))
+    }
+
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+        assert_eq!(mode, RecursiveMode::NonRecursive);
+        self.0.push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn unwatch(&mut self, _: &Path) -> notify::Result<()> {
+        Ok(())
+    }
+
+    fn kind() -> notify::WatcherKind {
+        notify::WatcherKind::NullWatcher
+    }
+}
+
+#[test]
+fn test_ignored_directories_are_not_watched() {
+    let temp_dir = create_ignore_tree();
+    let root = temp_dir.path();
+    let filter = FileFilter::default().matcher(root).unwrap();
+
+    let mut watcher = RecordingWatcher(Vec::new());
+    watch_tree(&mut watcher, root, &filter).unwrap();
+    let mut watched: Vec<String> = watcher
+        .0
+        .iter()
+        .map(|path| {
+            path.strip_prefix(root)
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+    watched.sort();
+    assert_eq!(watched, ["", "drafts", "guides", "vendor"]);
+}
+
+#[tokio::test]
+async fn test_watcher_skips_new_files_in_ignored_directories() {
+    let temp_dir = create_ignore_tree();
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files =
+        scan_markdown_files(&base_dir, &FileFilter::default()).expect("Failed to scan");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::new(router).expect("Failed to create test server");
+
+    fs::write(
+        temp_dir.path().join("node_modules/pkg/CHANGELOG.md"),
+        "# Ignored",
+    )
+    .expect("Failed to write");
+    fs::write(temp_dir.path().join("guides/new.md"), "# New").expect("Failed to write");
+
+    let added = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        while server.get("/guides/new.md").await.status_code() != 200 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(added.is_ok(), "new file should be tracked");
+
+    let response = server.get("/node_modules/pkg/CHANGELOG.md").await;
+    assert_eq!(response.status_code(), 404);
+
+    // Directories created later are watched too, including what was written
+    // into them before the watch was added
+    fs::create_dir_all(temp_dir.path().join("later/nested")).expect("Failed to create dir");
+    fs::write(temp_dir.path().join("later/nested/early.md"), "# Early").expect("Failed to write");
+    let added = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        while server.get("/later/nested/early.md").await.status_code() != 200 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(added.is_ok(), "file in a new directory should be tracked");
+
+    fs::write(temp_dir.path().join("later/nested/late.md"), "# Late").expect("Failed to write");
+    let added = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        while server.get("/later/nested/late.md").await.status_code() != 200 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(added.is_ok(), "new directory should be watched");
+}
+
 #[test]
 fn test_scan_markdown_files_case_insensitive() {
     let temp_dir = tempdir().expect("Failed to create temp dir");
@@ -127,7 +365,8 @@ fn test_scan_markdown_files_case_insensitive() {
     fs::write(temp_dir.path().join("test3.Md"), "# Test 3").expect("Failed to write");
     fs::write(temp_dir.path().join("test4.MARKDOWN"), "# Test 4").expect("Failed to write");
 
-    let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+    let result =
+        scan_markdown_files(temp_dir.path(), &FileFilter::default()).expect("Failed to scan");
 
     assert_eq!(result.len(), 4);
 }
@@ -144,6 +383,19 @@ fn test_format_host() {
     assert_eq!(format_host("2001:db8::1", 8080), "[2001:db8::1]:8080");
 }
 
+#[test]
+fn test_format_url() {
+    assert_eq!(
+        format_url("127.0.0.1", 3000, false),
+        "http://127.0.0.1:3000"
+    );
+    assert_eq!(
+        format_url("localhost", 8443, true),
+        "https://localhost:8443"
+    );
+    assert_eq!(format_url("::1", 3000, true), "https://[::1]:3000");
+}
+
 #[test]
 fn test_browsable_host() {
     assert_eq!(browsable_host("0.0.0.0"), "127.0.0.1");
@@ -155,6 +407,61 @@ fn test_browsable_host() {
     assert_eq!(browsable_host("example.com"), "example.com");
 }
 
+#[test]
+fn test_is_loopback_host() {
+    assert!(is_loopback_host("127.0.0.1"));
+    assert!(is_loopback_host("::1"));
+    assert!(is_loopback_host("localhost"));
+    assert!(!is_loopback_host("0.0.0.0"));
+    assert!(!is_loopback_host("::"));
+    assert!(!is_loopback_host("192.168.1.1"));
+    assert!(!is_loopback_host("example.com"));
+}
+
+#[test]
+fn test_allowed_hosts() {
+    let mut allowed = AllowedHosts::default();
+    assert!(allowed.allows("localhost:3000"));
+    assert!(allowed.allows("LOCALHOST."));
+    assert!(allowed.allows("127.0.0.1:3000"));
+    assert!(allowed.allows("192.168.1.20"));
+    assert!(allowed.allows("[::1]:3000"));
+    assert!(!allowed.allows("evil.example:3000"));
+    assert!(!allowed.allows("localhost.evil.example"));
+
+    allowed.allow("devbox.lan");
+    allowed.allow("0.0.0.0");
+    assert!(allowed.allows("DevBox.lan:3000"));
+    assert!(!allowed.allows("evil.example"));
+
+    assert!(AllowedHosts::new(vec!["docs.internal".to_string()]).allows("docs.internal:80"));
+    assert!(AllowedHosts::new(vec!["*".to_string()]).allows("evil.example:3000"));
+}
+
+#[test]
+fn test_host_name() {
+    assert_eq!(host_name("localhost:3000"), "localhost");
+    assert_eq!(host_name("example.com"), "example.com");
+    assert_eq!(host_name("[::1]:3000"), "::1");
+    assert_eq!(host_name("::1"), "::1");
+    assert_eq!(host_name("example.com.:8080"), "example.com");
+}
+
+#[test]
+fn test_auth_parsing() {
+    assert!(matches!(Auth::basic("ada:s3cret:x"), Ok(Auth::Basic(c)) if c == "ada:s3cret:x"));
+    assert!(Auth::basic("ada").is_err());
+    assert!(Auth::basic(":s3cret").is_err());
+    assert!(Auth::basic("ada:").is_err());
+
+    let (Ok(Auth::Token(a)), Ok(Auth::Token(b))) = (Auth::random_token(), Auth::random_token())
+    else {
+        panic!("expected tokens");
+    };
+    assert_eq!(a.len(), 43);
+    assert_ne!(a, b);
+}
+
 use axum_test::TestServer;
 use std::time::Duration;
 use tempfile::{Builder, NamedTempFile, TempDir};
@@ -188,8 +495,13 @@ fn create_test_server_impl(content: &str, use_http: bool) -> (TestServer, NamedT
     let tracked_files = vec![canonical_path];
     let is_directory_mode = false;
 
-    let router = new_router(base_dir, tracked_files, is_directory_mode)
-        .expect("Failed to create router");
+    let router = new_router(
+        base_dir,
+        tracked_files,
+        is_directory_mode,
+        &ServeOptions::default(),
+    )
+    .expect("Failed to create router");
 
     let server = if use_http {
         TestServer::builder()
@@ -211,7 +523,7 @@ async fn create_test_server_with_http(content: &str) -> (TestServer, NamedTempFi
     create_test_server_impl(content, true)
 }
 
-fn create_directory_server_impl(use_http: bool) -> (TestServer, TempDir) {
+fn create_directory_server_impl(use_http: bool, options: &ServeOptions) -> (TestServer, TempDir) {
     let temp_dir = tempdir().expect("Failed to create temp dir");
 
     fs::write(temp_dir.path().join("test1.md"), TEST_FILE_1_CONTENT)
@@ -222,10 +534,11 @@ fn create_directory_server_impl(use_http: bool) -> (TestServer, TempDir) {
         .expect("Failed to write test3.md");
 
     let base_dir = temp_dir.path().to_path_buf();
-    let tracked_files = scan_markdown_files(&base_dir).expect("Failed to scan markdown files");
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
     let is_directory_mode = true;
 
-    let router = new_router(base_dir, tracked_files, is_directory_mode)
+    let router = new_router(base_dir, tracked_files, is_directory_mode, options)
         .expect("Failed to create router");
 
     let server = if use_http {
@@ -241,11 +554,39 @@ fn create_directory_server_impl(use_http: bool) -> (TestServer, TempDir) {
 }
 
 async fn create_directory_server() -> (TestServer, TempDir) {
-    create_directory_server_impl(false)
+    create_directory_server_impl(false, &ServeOptions::default())
 }
 
 async fn create_directory_server_with_http() -> (TestServer, TempDir) {
-    create_directory_server_impl(true)
+    create_directory_server_impl(true, &ServeOptions::default())
+}
+
+async fn create_directory_server_with_options(options: ServeOptions) -> (TestServer, TempDir) {
+    create_directory_server_impl(true, &options)
+}
+
+/// Waits for an `Update` whose content contains `needle`, skipping messages
+/// caused by intermediate watcher events such as the truncate half of a write.
+async fn receive_update_containing(
+    websocket: &mut axum_test::TestWebSocket,
+    needle: &str,
+) -> (String, Option<usize>) {
+    tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        loop {
+            if let ServerMessage::Update {
+                html,
+                changed_block,
+                ..
+            } = websocket.receive_json().await
+            {
+                if html.contains(needle) {
+                    return (html, changed_block);
+                }
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Timeout waiting for update containing {needle:?}"))
 }
 
 #[tokio::test]
@@ -258,7 +599,7 @@ async fn test_server_starts_and_serves_basic_markdown() {
     assert_eq!(response.status_code(), 200);
     let body = response.text();
 
-    assert!(body.contains("<h1>Hello World</h1>"));
+    assert!(body.contains("<h1 id=\"hello-world\">Hello World</h1>"));
     assert!(body.contains("<strong>bold</strong>"));
     assert!(body.contains("theme-toggle"));
     assert!(body.contains("openThemeModal"));
@@ -337,7 +678,8 @@ fn main() {
     assert!(body.contains("<td>John</td>"));
     assert!(body.contains("<del>deleted text</del>"));
     assert!(body.contains("<pre>"));
-    assert!(body.contains("fn main()"));
+    assert!(body.contains(r#"<span class="hl-storage hl-type hl-function hl-rust">fn</span>"#));
+    assert!(body.contains(r#"<span class="hl-entity hl-name hl-function hl-rust">main</span>"#));
 }
 
 #[tokio::test]
@@ -371,8 +713,13 @@ async fn test_image_serving() {
     let base_dir = temp_dir.path().to_path_buf();
     let tracked_files = vec![md_path];
     let is_directory_mode = false;
-    let router = new_router(base_dir, tracked_files, is_directory_mode)
-        .expect("Failed to create router");
+    let router = new_router(
+        base_dir,
+        tracked_files,
+        is_directory_mode,
+        &ServeOptions::default(),
+    )
+    .expect("Failed to create router");
     let server = TestServer::new(router).expect("Failed to create test server");
 
     let response = server.get("/").await;
@@ -400,14 +747,135 @@ async fn test_non_image_files_not_served() {
     let base_dir = temp_dir.path().to_path_buf();
     let tracked_files = vec![md_path];
     let is_directory_mode = false;
-    let router = new_router(base_dir, tracked_files, is_directory_mode)
-        .expect("Failed to create router");
+    let router = new_router(
+        base_dir,
+        tracked_files,
+        is_directory_mode,
+        &ServeOptions::default(),
+    )
+    .expect("Failed to create router");
     let server = TestServer::new(router).expect("Failed to create test server");
 
     let response = server.get("/secret.txt").await;
     assert_eq!(response.status_code(), 404);
 }
 
+#[test]
+fn test_static_content_type() {
+    assert_eq!(static_content_type("report.pdf"), Some("application/pdf"));
+    assert_eq!(static_content_type("demo.MP4"), Some("video/mp4"));
+    assert_eq!(static_content_type("talk.mp3"), Some("audio/mpeg"));
+    assert_eq!(
+        static_content_type("data/results.csv"),
+        Some("text/csv; charset=utf-8")
+    );
+    assert_eq!(
+        static_content_type("attachment.html"),
+        Some("text/html; charset=utf-8")
+    );
+    assert_eq!(static_content_type("logo.svg"), Some("image/svg+xml"));
+
+    assert_eq!(static_content_type("secret.txt"), None);
+    assert_eq!(static_content_type(".env"), None);
+    assert_eq!(static_content_type("main.rs"), None);
+    assert_eq!(static_content_type("Makefile"), None);
+}
+
+fn create_asset_server() -> (TestServer, TempDir) {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let md_path = temp_dir.path().join("test.md");
+    fs::write(&md_path, "# Test").expect("Failed to write markdown file");
+    fs::create_dir(temp_dir.path().join("media")).expect("Failed to create media dir");
+    fs::write(temp_dir.path().join("media/clip.mp4"), b"0123456789")
+        .expect("Failed to write clip.mp4");
+    fs::write(temp_dir.path().join("report.pdf"), b"%PDF-1.4").expect("Failed to write pdf");
+    fs::write(temp_dir.path().join("data.csv"), "a,b\n1,2\n").expect("Failed to write csv");
+
+    let base_dir = temp_dir.path().to_path_buf();
+    let router = new_router(base_dir, vec![md_path], false, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::new(router).expect("Failed to create test server");
+    (server, temp_dir)
+}
+
+#[tokio::test]
+async fn test_non_image_assets_are_served() {
+    let (server, _temp_dir) = create_asset_server();
+
+    let response = server.get("/report.pdf").await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.header("content-type"), "application/pdf");
+    assert_eq!(response.as_bytes().as_ref(), b"%PDF-1.4");
+
+    let response = server.get("/data.csv").await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.header("content-type"), "text/csv; charset=utf-8");
+    assert_eq!(response.text(), "a,b\n1,2\n");
+
+    let response = server.get("/media/missing.mp4").await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_active_attachments_are_sandboxed() {
+    let (server, temp_dir) = create_asset_server();
+    fs::write(
+        temp_dir.path().join("report.html"),
+        "<script>fetch('/test.md')</script>",
+    )
+    .expect("Failed to write html");
+    fs::write(temp_dir.path().join("logo.svg"), "<svg></svg>").expect("Failed to write svg");
+
+    for path in ["/report.html", "/logo.svg"] {
+        let response = server.get(path).await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("content-security-policy"), "sandbox");
+    }
+
+    let response = server.get("/report.pdf").await;
+    assert!(response.maybe_header("content-security-policy").is_none());
+}
+
+#[tokio::test]
+async fn test_range_requests_for_media() {
+    let (server, _temp_dir) = create_asset_server();
+
+    let response = server
+        .get("/media/clip.mp4")
+        .add_header(header::RANGE, HeaderValue::from_static("bytes=2-5"))
+        .await;
+    assert_eq!(response.status_code(), 206);
+    assert_eq!(response.header("content-type"), "video/mp4");
+    assert_eq!(response.header("content-range"), "bytes 2-5/10");
+    assert_eq!(response.as_bytes().as_ref(), b"2345");
+
+    let response = server
+        .get("/media/clip.mp4")
+        .add_header(header::RANGE, HeaderValue::from_static("bytes=20-"))
+        .await;
+    assert_eq!(response.status_code(), 416);
+
+    let response = server.get("/media/clip.mp4").await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.header("accept-ranges"), "bytes");
+    assert_eq!(response.as_bytes().as_ref(), b"0123456789");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_asset_symlink_outside_base_dir_is_forbidden() {
+    let outside_dir = tempdir().expect("Failed to create temp dir");
+    let outside = outside_dir.path().join("private.pdf");
+    fs::write(&outside, b"%PDF-private").expect("Failed to write pdf");
+
+    let (server, temp_dir) = create_asset_server();
+    std::os::unix::fs::symlink(&outside, temp_dir.path().join("linked.pdf"))
+        .expect("Failed to create symlink");
+
+    let response = server.get("/linked.pdf").await;
+    assert_eq!(response.status_code(), 403);
+}
+
 #[tokio::test]
 async fn test_html_tags_in_markdown_are_rendered() {
     let markdown_content = r#"# HTML Test
@@ -612,19 +1080,19 @@ async fn test_directory_mode_serves_multiple_files() {
     let response1 = server.get("/test1.md").await;
     assert_eq!(response1.status_code(), 200);
     let body1 = response1.text();
-    assert!(body1.contains("<h1>Test 1</h1>"));
+    assert!(body1.contains("<h1 id=\"test-1\">Test 1</h1>"));
     assert!(body1.contains("Content of test1"));
 
     let response2 = server.get("/test2.markdown").await;
     assert_eq!(response2.status_code(), 200);
     let body2 = response2.text();
-    assert!(body2.contains("<h1>Test 2</h1>"));
+    assert!(body2.contains("<h1 id=\"test-2\">Test 2</h1>"));
     assert!(body2.contains("Content of test2"));
 
     let response3 = server.get("/test3.md").await;
     assert_eq!(response3.status_code(), 200);
     let body3 = response3.text();
-    assert!(body3.contains("<h1>Test 3</h1>"));
+    assert!(body3.contains("<h1 id=\"test-3\">Test 3</h1>"));
     assert!(body3.contains("Content of test3"));
 }
 
@@ -788,7 +1256,7 @@ async fn test_directory_mode_new_file_triggers_reload() {
     let new_file_response = server.get("/test4.md").await;
     assert_eq!(new_file_response.status_code(), 200);
     let new_file_body = new_file_response.text();
-    assert!(new_file_body.contains("<h1>Test 4</h1>"));
+    assert!(new_file_body.contains("<h1 id=\"test-4\">Test 4</h1>"));
     assert!(new_file_body.contains("This is a new file"));
 }
 
@@ -912,106 +1380,615 @@ async fn test_no_404_during_editor_save_sequence() {
     let _ = fs::remove_file(&backup_path);
 }
 
-#[tokio::test]
-async fn test_yaml_frontmatter_is_stripped() {
-    let (server, _temp_file) = create_test_server(YAML_FRONTMATTER_CONTENT).await;
-
-    let response = server.get("/").await;
+#[test]
+fn test_coalesce_events_keeps_one_change_per_path() {
+    use notify::event::{CreateKind, DataChange, ModifyKind, RemoveKind};
+    use notify::EventKind;
+
+    let event = |kind: EventKind, path: &str| Event::new(kind).add_path(PathBuf::from(path));
+    let write = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+    let events = vec![
+        event(EventKind::Create(CreateKind::File), "/docs/a.md"),
+        event(write, "/docs/a.md"),
+        event(write, "/docs/b.md"),
+        event(EventKind::Remove(RemoveKind::File), "/docs/a.md"),
+        event(write, "/docs/a.md"),
+        event(write, "/docs/b.md"),
+    ];
 
-    assert_eq!(response.status_code(), 200);
-    let body = response.text();
+    let coalesced: Vec<(EventKind, PathBuf)> = coalesce_events(events)
+        .into_iter()
+        .map(|event| (event.kind, event.paths[0].clone()))
+        .collect();
+    assert_eq!(
+        coalesced,
+        vec![
+            (
+                EventKind::Create(CreateKind::File),
+                PathBuf::from("/docs/a.md")
+            ),
+            (write, PathBuf::from("/docs/b.md")),
+            (
+                EventKind::Remove(RemoveKind::File),
+                PathBuf::from("/docs/a.md")
+            ),
+        ]
+    );
+}
 
-    assert!(!body.contains("title: Test Post"));
-    assert!(!body.contains("author: Name"));
-    assert!(body.contains("<h1>Test Post</h1>"));
+#[test]
+fn test_coalesce_events_refreshes_renamed_path_once() {
+    use notify::event::{CreateKind, DataChange, ModifyKind, RenameMode};
+    use notify::EventKind;
+
+    let rename = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+        .add_path(PathBuf::from("/docs/a.md.tmp"))
+        .add_path(PathBuf::from("/docs/a.md"));
+    let write = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+        .add_path(PathBuf::from("/docs/a.md"));
+    let create =
+        Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("/docs/b.md"));
+
+    // The rename refreshes a.md, so the write after it adds nothing
+    let coalesced = coalesce_events(vec![rename.clone(), write.clone()]);
+    assert_eq!(coalesced, vec![rename.clone()]);
+
+    // After a write, the rename only still says the old path went away
+    let coalesced = coalesce_events(vec![create.clone(), write.clone(), rename]);
+    assert_eq!(
+        coalesced,
+        vec![
+            create,
+            write,
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+                .add_path(PathBuf::from("/docs/a.md.tmp")),
+        ]
+    );
 }
 
 #[tokio::test]
-async fn test_toml_frontmatter_is_stripped() {
-    let (server, _temp_file) = create_test_server(TOML_FRONTMATTER_CONTENT).await;
+async fn test_burst_of_writes_sends_one_update() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+    let mut websocket = server
+        .get_websocket("/ws?file=test1.md")
+        .await
+        .into_websocket()
+        .await;
 
-    let response = server.get("/").await;
+    let file_path = temp_dir.path().join("test1.md");
+    for revision in 1..=5 {
+        fs::write(&file_path, format!("# Test 1\n\nRevision {revision}\n"))
+            .expect("Failed to write file");
+    }
 
-    assert_eq!(response.status_code(), 200);
-    let body = response.text();
+    let message = tokio::time::timeout(
+        Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+        websocket.receive_json::<ServerMessage>(),
+    )
+    .await
+    .expect("Timeout waiting for update");
+    let ServerMessage::Update { html, .. } = message else {
+        panic!("expected an update, got {message:?}");
+    };
+    assert!(html.contains("Revision 5"));
 
-    assert!(!body.contains("title = \"Test Post\""));
-    assert!(body.contains("<h1>Test Post</h1>"));
+    let extra = tokio::time::timeout(
+        Duration::from_millis(300),
+        websocket.receive_json::<ServerMessage>(),
+    )
+    .await;
+    assert!(extra.is_err(), "burst should produce a single update");
 }
 
 #[tokio::test]
-async fn test_temp_file_rename_triggers_reload_single_file_mode() {
-    let (server, temp_file) =
-        create_test_server_with_http("# Original\n\nOriginal content").await;
-
-    let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
-
-    let file_path = temp_file.path().to_path_buf();
-    let temp_write_path = file_path.with_extension("md.tmp.12345");
+async fn test_polling_watcher_picks_up_changes() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let file_path = temp_dir.path().join("notes.md");
+    fs::write(&file_path, "# Notes\n\nFirst draft\n").expect("Failed to write file");
 
-    let initial_response = server.get("/").await;
-    assert_eq!(initial_response.status_code(), 200);
-    assert!(
-        initial_response.text().contains("Original content"),
-        "File should be tracked and serving content before edit"
-    );
+    let options = ServeOptions {
+        poll: Some(Duration::from_millis(50)),
+        ..ServeOptions::default()
+    };
+    let base_dir = temp_dir.path().to_path_buf();
+    let router = new_router(base_dir, vec![file_path.clone()], true, &options)
+        .expect("Failed to create router");
+    let server = TestServer::builder()
+        .http_transport()
+        .build(router)
+        .expect("Failed to create test server");
+    let mut websocket = server
+        .get_websocket("/ws?file=notes.md")
+        .await
+        .into_websocket()
+        .await;
 
+    // Modification times can be coarse; make sure the write is newer
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    fs::write(&file_path, "# Notes\n\nSecond draft\n").expect("Failed to write file");
+    receive_update_containing(&mut websocket, "Second draft").await;
+
+    fs::write(temp_dir.path().join("added.md"), "# Added\n").expect("Failed to write file");
+    wait_for_status(&server, "/added.md", 200).await;
+
+    // New directories join the scan
+    fs::create_dir(temp_dir.path().join("guides")).expect("Failed to create dir");
+    fs::write(temp_dir.path().join("guides/setup.md"), "# Setup\n").expect("Failed to write file");
+    wait_for_status(&server, "/guides/setup.md", 200).await;
+    let mut websocket = server
+        .get_websocket("/ws?file=guides/setup.md")
+        .await
+        .into_websocket()
+        .await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
     fs::write(
-        &temp_write_path,
-        "# Updated\n\nUpdated content via temp file",
+        temp_dir.path().join("guides/setup.md"),
+        "# Setup\n\nEdited\n",
     )
-    .expect("Failed to write temp file");
-
-    tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+    .expect("Failed to write file");
+    receive_update_containing(&mut websocket, "Edited").await;
+}
 
-    fs::rename(&temp_write_path, &file_path).expect("Failed to rename temp file");
+fn create_lazy_state(temp_dir: &tempfile::TempDir, render_cache: usize) -> MarkdownState {
+    for name in ["a.md", "b.md", "c.md"] {
+        fs::write(
+            temp_dir.path().join(name),
+            format!("---\ntitle: Page {name}\n---\n\n# {name}\n"),
+        )
+        .expect("Failed to write file");
+    }
+    let base_dir = temp_dir
+        .path()
+        .canonicalize()
+        .expect("Failed to canonicalize");
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
+    let render_cache = NonZeroUsize::new(render_cache).unwrap();
+    MarkdownState::new(base_dir, tracked_files, true, render_cache).expect("Failed to create state")
+}
 
-    tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+#[tokio::test]
+async fn test_files_render_lazily_into_bounded_cache() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let state = create_lazy_state(&temp_dir, 2);
 
-    let update_result = tokio::time::timeout(
-        Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
-        websocket.receive_json::<ServerMessage>(),
-    )
-    .await;
+    // Nothing is rendered up front, but the sidebar already has every title
+    assert!(lock(&state.renders).is_empty());
+    assert_eq!(state.titles()["c.md"], "Page c.md");
 
-    match update_result {
-        Ok(update_message) => {
-            if let ServerMessage::Reload = update_message {
-                // Success
-            } else {
-                panic!("Expected Reload message after temp file rename");
-            }
-        }
-        Err(_) => {
-            panic!("Timeout waiting for WebSocket update after temp file rename");
-        }
+    for name in ["a.md", "b.md", "c.md"] {
+        state.refresh_file(name).expect("Failed to render");
     }
-
-    let final_response = server.get("/").await;
-    assert_eq!(final_response.status_code(), 200);
-    let final_body = final_response.text();
-    assert!(
-        final_body.contains("Updated content via temp file"),
-        "Should serve updated content after temp file rename"
-    );
+    assert_eq!(lock(&state.renders).len(), 2);
     assert!(
-        !final_body.contains("Original content"),
-        "Should not serve old content"
+        !lock(&state.renders).contains("a.md"),
+        "least recently used is dropped"
     );
+
+    let (status, Html(page)) = render_markdown(&state, "b.md", PageTarget::Live);
+    assert_eq!(status, StatusCode::OK);
+    assert!(page.contains("b.md</h1>"));
+    assert!(page.contains("Page a.md"), "sidebar lists unrendered files");
+
+    // An unchanged file isn't rendered again
+    let cached = state.cached_render("b.md").expect("b.md is cached");
+    state.refresh_file("b.md").expect("Failed to refresh");
+    let current = state.cached_render("b.md").expect("b.md is cached");
+    assert!(Arc::ptr_eq(&cached, &current));
 }
 
 #[tokio::test]
-async fn test_temp_file_rename_triggers_reload_directory_mode() {
-    let (server, temp_dir) = create_directory_server_with_http().await;
+async fn test_prewarm_fills_render_cache() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let state = Arc::new(create_lazy_state(&temp_dir, 2));
 
-    let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+    prewarm_renders(state.clone()).await;
 
-    let file_path = temp_dir.path().join("test1.md");
-    let temp_write_path = temp_dir.path().join("test1.md.tmp.67890");
+    let mut rendered: Vec<String> = lock(&state.renders)
+        .iter()
+        .map(|(key, _)| key.clone())
+        .collect();
+    rendered.sort();
+    assert_eq!(rendered, vec!["a.md", "b.md"]);
+}
 
-    let initial_response = server.get("/test1.md").await;
-    assert_eq!(initial_response.status_code(), 200);
-    assert!(
+#[tokio::test]
+async fn test_session_baseline_outlives_render_cache() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let state = create_lazy_state(&temp_dir, 1);
+    let change = ServerMessage::FileChanged {
+        file: "a.md".to_string(),
+    };
+
+    // Edited before its first render, then evicted by another page
+    fs::write(temp_dir.path().join("a.md"), "# a.md\n\nAdded\n").expect("Failed to write");
+    state.refresh_file("b.md").expect("Failed to render");
+    assert!(state.cached_render("a.md").is_none());
+
+    let Some(ServerMessage::Update { html, .. }) =
+        state.message_for_page(&change, "a.md", ChangesMode::Session)
+    else {
+        panic!("expected an update for an uncached page");
+    };
+    assert!(html.contains("Added"));
+    assert_eq!(html.matches("diff-inserted").count(), 1);
+
+    state.refresh_file("b.md").expect("Failed to render");
+    let Some(ServerMessage::Update { changed_block, .. }) =
+        state.message_for_page(&change, "a.md", ChangesMode::Off)
+    else {
+        panic!("expected an update for an evicted page");
+    };
+    assert_eq!(changed_block, None);
+
+    // Without anything left to serve, the page reloads
+    fs::remove_file(temp_dir.path().join("a.md")).expect("Failed to delete");
+    assert!(state.remove_missing_files());
+    assert_eq!(
+        state.message_for_page(&change, "a.md", ChangesMode::Off),
+        Some(ServerMessage::Reload)
+    );
+}
+
+#[tokio::test]
+async fn test_missing_file_is_served_as_last_read() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let state = create_lazy_state(&temp_dir, 2);
+
+    // Never rendered, and gone until an editor recreates it
+    fs::remove_file(temp_dir.path().join("c.md")).expect("Failed to delete");
+    state.refresh_file("c.md").expect("Failed to render");
+
+    let (status, Html(page)) = render_markdown(&state, "c.md", PageTarget::Live);
+    assert_eq!(status, StatusCode::OK);
+    assert!(page.contains("c.md</h1>"));
+}
+
+#[tokio::test]
+async fn test_watcher_sees_edits_that_keep_the_modification_time() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let state = Arc::new(create_lazy_state(&temp_dir, 2));
+    let filter = FileFilter::default()
+        .matcher(&state.base_dir)
+        .expect("Failed to build matcher");
+    let path = temp_dir.path().join("a.md");
+    state.refresh_file("a.md").expect("Failed to render");
+
+    // Saved within the filesystem's timestamp resolution
+    let modified = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .expect("Failed to read modification time");
+    fs::write(&path, "# a.md\n\nEdited\n").expect("Failed to write");
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(modified))
+        .expect("Failed to restore modification time");
+
+    // Requests go by the modification time, the watcher's event doesn't
+    state.refresh_file("a.md").expect("Failed to render");
+    assert!(!state.cached_render("a.md").unwrap().html.contains("Edited"));
+    handle_markdown_file_change(&path, &state, &filter).await;
+    assert!(state.cached_render("a.md").unwrap().html.contains("Edited"));
+}
+
+#[tokio::test]
+async fn test_changes_to_unrendered_files_only_update_the_sidebar() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let state = Arc::new(create_lazy_state(&temp_dir, 2));
+    let filter = FileFilter::default()
+        .matcher(&state.base_dir)
+        .expect("Failed to build matcher");
+    let mut changes = state.change_tx.subscribe();
+
+    let path = temp_dir.path().join("b.md");
+    fs::write(&path, "---\ntitle: Renamed\n---\n\n# b.md\n").expect("Failed to write");
+    handle_markdown_file_change(&path, &state, &filter).await;
+
+    assert!(state.cached_render("b.md").is_none());
+    assert_eq!(state.titles()["b.md"], "Renamed");
+    assert_eq!(changes.try_recv(), Ok(ServerMessage::Reload));
+}
+
+#[tokio::test]
+async fn test_pages_are_served_while_files_change() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+    let file_path = temp_dir.path().join("test1.md");
+
+    let writer = tokio::spawn(async move {
+        for revision in 1..=20 {
+            fs::write(&file_path, format!("# Test 1\n\nRevision {revision}\n"))
+                .expect("Failed to write file");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    });
+
+    for _ in 0..10 {
+        let responses = futures_util::future::join_all(
+            [
+                "/test1.md",
+                "/test2.markdown",
+                "/test1.md",
+                "/api/search?q=test",
+            ]
+            .map(|path| std::future::IntoFuture::into_future(server.get(path))),
+        )
+        .await;
+        for response in responses {
+            assert_eq!(response.status_code(), 200);
+        }
+    }
+    writer.await.expect("Writer panicked");
+
+    tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        while !server.get("/test1.md").await.text().contains("Revision 20") {
+            tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+        }
+    })
+    .await
+    .expect("Timeout waiting for the last revision");
+}
+
+/// Polls `path` until it answers with `status`, or panics after the timeout.
+async fn wait_for_status(server: &TestServer, path: &str, status: u16) -> axum_test::TestResponse {
+    tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        loop {
+            let response = server.get(path).await;
+            if response.status_code() == status {
+                return response;
+            }
+            tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Timeout waiting for {path} to answer {status}"))
+}
+
+#[tokio::test]
+async fn test_deleted_file_is_removed_after_grace_period() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+    let mut websocket = server
+        .get_websocket("/ws?file=test1.md")
+        .await
+        .into_websocket()
+        .await;
+
+    fs::remove_file(temp_dir.path().join("test2.markdown")).expect("Failed to delete file");
+
+    // Still served while an editor might be recreating it
+    tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+    assert_eq!(server.get("/test2.markdown").await.status_code(), 200);
+
+    wait_for_status(&server, "/test2.markdown", 404).await;
+    let body = server.get("/test1.md").await.text();
+    assert!(!body.contains(r#"href="/test2.markdown""#));
+
+    let message = tokio::time::timeout(
+        Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+        websocket.receive_json::<ServerMessage>(),
+    )
+    .await
+    .expect("Timeout waiting for reload");
+    assert_eq!(message, ServerMessage::Reload);
+}
+
+#[tokio::test]
+async fn test_renamed_file_redirects_old_url() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+    fs::create_dir(temp_dir.path().join("moved")).expect("Failed to create dir");
+    tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+
+    fs::rename(
+        temp_dir.path().join("test1.md"),
+        temp_dir.path().join("moved/first file.md"),
+    )
+    .expect("Failed to rename file");
+
+    let response = wait_for_status(&server, "/test1.md", 307).await;
+    assert_eq!(response.header("location"), "/moved/first%20file.md");
+
+    let response = server.get("/moved/first%20file.md").await;
+    assert_eq!(response.status_code(), 200);
+    assert!(response.text().contains("Test 1"));
+
+    // Renaming again keeps the original URL pointing at the file
+    fs::rename(
+        temp_dir.path().join("moved/first file.md"),
+        temp_dir.path().join("final.md"),
+    )
+    .expect("Failed to rename file");
+    let response = wait_for_status(&server, "/moved/first%20file.md", 307).await;
+    assert_eq!(response.header("location"), "/final.md");
+    let response = server.get("/test1.md").await;
+    assert_eq!(response.header("location"), "/final.md");
+    let response = server.get("/print/test1.md?toc=1").await;
+    assert_eq!(response.header("location"), "/print/final.md?toc=1");
+}
+
+#[tokio::test]
+async fn test_copy_then_delete_is_not_a_rename() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+    let original = fs::read_to_string(temp_dir.path().join("test1.md")).expect("Failed to read");
+    fs::write(temp_dir.path().join("copy.md"), original).expect("Failed to write copy");
+    wait_for_status(&server, "/copy.md", 200).await;
+
+    // Same content, but the copy didn't appear in the burst that deleted it
+    fs::remove_file(temp_dir.path().join("test1.md")).expect("Failed to delete file");
+    wait_for_status(&server, "/test1.md", 404).await;
+}
+
+#[tokio::test]
+async fn test_yaml_frontmatter_is_stripped() {
+    let (server, _temp_file) = create_test_server(YAML_FRONTMATTER_CONTENT).await;
+
+    let response = server.get("/").await;
+
+    assert_eq!(response.status_code(), 200);
+    let body = response.text();
+
+    assert!(!body.contains("title: Test Post"));
+    assert!(!body.contains("author: Name"));
+    assert!(body.contains("<h1 id=\"test-post\">Test Post</h1>"));
+}
+
+#[tokio::test]
+async fn test_toml_frontmatter_is_stripped() {
+    let (server, _temp_file) = create_test_server(TOML_FRONTMATTER_CONTENT).await;
+
+    let response = server.get("/").await;
+
+    assert_eq!(response.status_code(), 200);
+    let body = response.text();
+
+    assert!(!body.contains("title = \"Test Post\""));
+    assert!(body.contains("<h1 id=\"test-post\">Test Post</h1>"));
+}
+
+#[test]
+fn test_frontmatter_parses_yaml_and_toml() {
+    let yaml = Frontmatter::parse(
+        "---\ntitle: Design Notes\nauthors: [Ada, Grace]\nstatus: draft\ndate: 2024-03-01\ntags: rust, docs\n---\n\n# Body\n",
+    )
+    .expect("YAML frontmatter should parse");
+    assert_eq!(
+        yaml,
+        Frontmatter {
+            title: Some("Design Notes".to_string()),
+            author: Some("Ada, Grace".to_string()),
+            status: Some("draft".to_string()),
+            date: Some("2024-03-01".to_string()),
+            tags: vec!["rust".to_string(), "docs".to_string()],
+        }
+    );
+
+    let toml = Frontmatter::parse(
+        "+++\ntitle = \"Design Notes\"\nauthor = \"Ada\"\ndate = 2024-03-01\ntags = [\"rust\", \"docs\"]\n+++\n",
+    )
+    .expect("TOML frontmatter should parse");
+    assert_eq!(toml.title.as_deref(), Some("Design Notes"));
+    assert_eq!(toml.author.as_deref(), Some("Ada"));
+    assert_eq!(toml.date.as_deref(), Some("2024-03-01"));
+    assert_eq!(toml.tags, vec!["rust", "docs"]);
+
+    assert_eq!(Frontmatter::parse("# No frontmatter\n"), None);
+    assert_eq!(Frontmatter::parse("---\nunterminated: true\n"), None);
+    assert_eq!(Frontmatter::parse("---\n- just\n- a list\n---\n"), None);
+}
+
+#[tokio::test]
+async fn test_frontmatter_renders_metadata_panel_and_title() {
+    let (server, _temp_file) = create_test_server(
+        "---\ntitle: <Release> Plan\nstatus: review\ntags: [q3]\n---\n\n# Heading\n",
+    )
+    .await;
+
+    let body = server.get("/").await.text();
+
+    assert!(body.contains("<title>&lt;Release&gt; Plan</title>"));
+    assert!(body.contains("<details class=\"frontmatter\" open>"));
+    assert!(body.contains("<summary>&lt;Release&gt; Plan</summary>"));
+    assert!(body.contains("<dt>Status</dt><dd>review</dd>"));
+    assert!(body.contains("<span class=\"frontmatter-tag\">q3</span>"));
+    assert!(body.contains("<h1 id=\"heading\">Heading</h1>"));
+}
+
+#[tokio::test]
+async fn test_sidebar_uses_frontmatter_title() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    fs::write(temp_dir.path().join("plain.md"), "# Plain\n").expect("Failed to write plain.md");
+    fs::write(
+        temp_dir.path().join("titled.md"),
+        "---\ntitle: Friendly Name\n---\n\n# Titled\n",
+    )
+    .expect("Failed to write titled.md");
+
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::new(router).expect("Failed to create test server");
+
+    let body = server.get("/plain.md").await.text();
+
+    assert!(body.contains("<title>Markdown Preview</title>"));
+    assert!(body.contains("<a href=\"/titled.md\">\n                    Friendly Name\n"));
+    assert!(body.contains("plain.md\n                </a>"));
+}
+
+#[tokio::test]
+async fn test_temp_file_rename_triggers_reload_single_file_mode() {
+    let (server, temp_file) =
+        create_test_server_with_http("# Original\n\nOriginal content").await;
+
+    let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+
+    let file_path = temp_file.path().to_path_buf();
+    let temp_write_path = file_path.with_extension("md.tmp.12345");
+
+    let initial_response = server.get("/").await;
+    assert_eq!(initial_response.status_code(), 200);
+    assert!(
+        initial_response.text().contains("Original content"),
+        "File should be tracked and serving content before edit"
+    );
+
+    fs::write(
+        &temp_write_path,
+        "# Updated\n\nUpdated content via temp file",
+    )
+    .expect("Failed to write temp file");
+
+    tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+
+    fs::rename(&temp_write_path, &file_path).expect("Failed to rename temp file");
+
+    tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+
+    let update_result = tokio::time::timeout(
+        Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+        websocket.receive_json::<ServerMessage>(),
+    )
+    .await;
+
+    match update_result {
+        Ok(update_message) => {
+            if let ServerMessage::Reload = update_message {
+                // Success
+            } else {
+                panic!("Expected Reload message after temp file rename");
+            }
+        }
+        Err(_) => {
+            panic!("Timeout waiting for WebSocket update after temp file rename");
+        }
+    }
+
+    let final_response = server.get("/").await;
+    assert_eq!(final_response.status_code(), 200);
+    let final_body = final_response.text();
+    assert!(
+        final_body.contains("Updated content via temp file"),
+        "Should serve updated content after temp file rename"
+    );
+    assert!(
+        !final_body.contains("Original content"),
+        "Should not serve old content"
+    );
+}
+
+#[tokio::test]
+async fn test_temp_file_rename_triggers_reload_directory_mode() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+
+    let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+
+    let file_path = temp_dir.path().join("test1.md");
+    let temp_write_path = temp_dir.path().join("test1.md.tmp.67890");
+
+    let initial_response = server.get("/test1.md").await;
+    assert_eq!(initial_response.status_code(), 200);
+    assert!(
         initial_response.text().contains("Content of test1"),
         "File should be tracked and serving content before edit"
     );
@@ -1148,7 +2125,8 @@ async fn test_subdirectory_image_serving() {
 
     let base_dir = temp_dir.path().to_path_buf();
     let tracked_files = vec![md_path];
-    let router = new_router(base_dir, tracked_files, false).expect("Failed to create router");
+    let router = new_router(base_dir, tracked_files, false, &ServeOptions::default())
+        .expect("Failed to create router");
     let server = TestServer::new(router).expect("Failed to create test server");
 
     // subdirectory image
@@ -1171,7 +2149,8 @@ async fn test_directory_traversal_blocked() {
 
     let base_dir = temp_dir.path().to_path_buf();
     let tracked_files = vec![md_path];
-    let router = new_router(base_dir, tracked_files, false).expect("Failed to create router");
+    let router = new_router(base_dir, tracked_files, false, &ServeOptions::default())
+        .expect("Failed to create router");
     let server = TestServer::new(router).expect("Failed to create test server");
 
     let response = server.get("/../../../etc/passwd").await;
@@ -1190,7 +2169,8 @@ async fn test_same_dir_image_still_works_with_wildcard_route() {
 
     let base_dir = temp_dir.path().to_path_buf();
     let tracked_files = vec![md_path];
-    let router = new_router(base_dir, tracked_files, false).expect("Failed to create router");
+    let router = new_router(base_dir, tracked_files, false, &ServeOptions::default())
+        .expect("Failed to create router");
     let server = TestServer::new(router).expect("Failed to create test server");
 
     let response = server.get("/photo.jpg").await;
@@ -1216,7 +2196,8 @@ fn test_scan_markdown_files_deep_nesting() {
     fs::create_dir(&level3).expect("Failed to create level3");
     fs::write(level3.join("l3.md"), "# Level 3").expect("Failed to write");
 
-    let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+    let result =
+        scan_markdown_files(temp_dir.path(), &FileFilter::default()).expect("Failed to scan");
 
     assert_eq!(result.len(), 4);
 }
@@ -1232,14 +2213,16 @@ async fn test_directory_mode_serves_nested_files() {
     fs::write(docs_dir.join("guide.md"), "# Guide\n\nGuide content").expect("Failed to write");
 
     let base_dir = temp_dir.path().to_path_buf();
-    let tracked_files = scan_markdown_files(&base_dir).expect("Failed to scan");
-    let router = new_router(base_dir, tracked_files, true).expect("Failed to create router");
+    let tracked_files =
+        scan_markdown_files(&base_dir, &FileFilter::default()).expect("Failed to scan");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
     let server = TestServer::new(router).expect("Failed to create test server");
 
     let response = server.get("/docs/guide.md").await;
     assert_eq!(response.status_code(), 200);
     let body = response.text();
-    assert!(body.contains("<h1>Guide</h1>"));
+    assert!(body.contains("<h1 id=\"guide\">Guide</h1>"));
     assert!(body.contains("Guide content"));
 }
 
@@ -1254,8 +2237,10 @@ async fn test_directory_mode_tree_sidebar() {
     fs::write(docs_dir.join("guide.md"), "# Guide").expect("Failed to write");
 
     let base_dir = temp_dir.path().to_path_buf();
-    let tracked_files = scan_markdown_files(&base_dir).expect("Failed to scan");
-    let router = new_router(base_dir, tracked_files, true).expect("Failed to create router");
+    let tracked_files =
+        scan_markdown_files(&base_dir, &FileFilter::default()).expect("Failed to scan");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
     let server = TestServer::new(router).expect("Failed to create test server");
 
     let response = server.get("/readme.md").await;
@@ -1280,8 +2265,10 @@ async fn test_nested_file_active_highlighting() {
     fs::write(docs_dir.join("guide.md"), "# Guide").expect("Failed to write");
 
     let base_dir = temp_dir.path().to_path_buf();
-    let tracked_files = scan_markdown_files(&base_dir).expect("Failed to scan");
-    let router = new_router(base_dir, tracked_files, true).expect("Failed to create router");
+    let tracked_files =
+        scan_markdown_files(&base_dir, &FileFilter::default()).expect("Failed to scan");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
     let server = TestServer::new(router).expect("Failed to create test server");
 
     let response = server.get("/docs/guide.md").await;
@@ -1296,3 +2283,1372 @@ async fn test_nested_file_active_highlighting() {
     let active_count = body.matches(r#"class="active""#).count();
     assert_eq!(active_count, 1, "Should have exactly one active link");
 }
+
+#[test]
+fn test_referenced_images_resolves_against_page_directory() {
+    let html = r#"<p><img src="img/a.png" alt="a"><img src="../shared/b.svg"><img src="/root.jpg"></p>
+<img src="https://example.com/remote.png"><img src="data:image/png;base64,xyz"><script src="app.js"></script>"#;
+
+    let images = referenced_images("docs/guide.md", html);
+
+    let expected: HashSet<String> = ["docs/img/a.png", "shared/b.svg", "root.jpg"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    assert_eq!(images, expected);
+
+    let top_level = referenced_images("readme.md", r#"<img src="./img/a.png">"#);
+    assert!(top_level.contains("img/a.png"));
+}
+
+#[tokio::test]
+async fn test_directory_mode_update_targets_changed_page() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+
+    let mut changed_page = server
+        .get_websocket("/ws?file=test1.md")
+        .await
+        .into_websocket()
+        .await;
+    let mut other_page = server
+        .get_websocket("/ws?file=test3.md")
+        .await
+        .into_websocket()
+        .await;
+
+    fs::write(temp_dir.path().join("test1.md"), "# Test 1\n\nEdited").expect("Failed to modify");
+
+    receive_update_containing(&mut changed_page, "Edited").await;
+
+    let unrelated = tokio::time::timeout(
+        Duration::from_millis(FILE_WATCH_DELAY_MS * 5),
+        other_page.receive_json::<ServerMessage>(),
+    )
+    .await;
+    assert!(
+        unrelated.is_err(),
+        "Page showing another file should not reload"
+    );
+
+    fs::write(temp_dir.path().join("test3.md"), "# Test 3\n\nEdited").expect("Failed to modify");
+
+    receive_update_containing(&mut other_page, "Edited").await;
+}
+
+#[tokio::test]
+async fn test_image_change_reloads_only_embedding_pages() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    fs::write(
+        temp_dir.path().join("with-image.md"),
+        "# A\n\n![pic](pic.png)",
+    )
+    .expect("Failed to write");
+    fs::write(temp_dir.path().join("without-image.md"), "# B").expect("Failed to write");
+    fs::write(temp_dir.path().join("pic.png"), [0x89, 0x50]).expect("Failed to write image");
+
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files =
+        scan_markdown_files(&base_dir, &FileFilter::default()).expect("Failed to scan");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::builder()
+        .http_transport()
+        .build(router)
+        .expect("Failed to create test server");
+
+    let mut embedding = server
+        .get_websocket("/ws?file=with-image.md")
+        .await
+        .into_websocket()
+        .await;
+    let mut other = server
+        .get_websocket("/ws?file=without-image.md")
+        .await
+        .into_websocket()
+        .await;
+
+    fs::write(temp_dir.path().join("pic.png"), [0x89, 0x50, 0x4E]).expect("Failed to update image");
+
+    let reload = tokio::time::timeout(
+        Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+        embedding.receive_json::<ServerMessage>(),
+    )
+    .await
+    .expect("Timeout waiting for reload after image change");
+    assert_eq!(reload, ServerMessage::Reload);
+
+    let unrelated = tokio::time::timeout(
+        Duration::from_millis(FILE_WATCH_DELAY_MS * 5),
+        other.receive_json::<ServerMessage>(),
+    )
+    .await;
+    assert!(
+        unrelated.is_err(),
+        "Page without the image should not reload"
+    );
+}
+
+#[tokio::test]
+async fn test_update_carries_rendered_content() {
+    let (server, temp_file) = create_test_server_with_http("# Original").await;
+    let page = temp_file
+        .path()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .expect("temp file name")
+        .to_string();
+
+    let mut websocket = server
+        .get_websocket(&format!("/ws?file={page}"))
+        .await
+        .into_websocket()
+        .await;
+
+    fs::write(&temp_file, "# Updated\n\n| a |\n|---|\n| b |").expect("Failed to modify file");
+
+    let (html, _) = receive_update_containing(&mut websocket, "<td>b</td>").await;
+
+    assert!(html.contains("<h1 id=\"updated\">Updated</h1>"));
+    assert!(!html.contains("<html"), "Update should carry only the body");
+}
+
+#[tokio::test]
+async fn test_new_file_falls_back_to_reload_for_open_pages() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+
+    let mut websocket = server
+        .get_websocket("/ws?file=test1.md")
+        .await
+        .into_websocket()
+        .await;
+
+    fs::write(temp_dir.path().join("test4.md"), "# Test 4").expect("Failed to create file");
+
+    let message = tokio::time::timeout(
+        Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+        websocket.receive_json::<ServerMessage>(),
+    )
+    .await
+    .expect("Timeout waiting for reload after new file");
+
+    assert_eq!(
+        message,
+        ServerMessage::Reload,
+        "Sidebar changes need a full reload"
+    );
+}
+
+#[test]
+fn test_top_level_blocks() {
+    let html = "<h1>Title</h1>\n<p>One <em>two</em></p>\n<hr />\n<!-- note -->\n<ul>\n<li><p>nested</p></li>\n</ul>\n<img src=\"a.png\">";
+
+    let blocks = top_level_blocks(html);
+
+    assert_eq!(
+        blocks,
+        vec![
+            "<h1>Title</h1>",
+            "<p>One <em>two</em></p>",
+            "<hr />",
+            "<ul>\n<li><p>nested</p></li>\n</ul>",
+            "<img src=\"a.png\">",
+        ]
+    );
+}
+
+#[test]
+fn test_first_changed_block() {
+    let old = "<h1>A</h1>\n<p>one</p>\n<p>two</p>\n";
+
+    assert_eq!(first_changed_block(old, old), None);
+    assert_eq!(
+        first_changed_block(old, "<h1>A</h1>\n<p>one</p>\n<p>TWO</p>\n"),
+        Some(2)
+    );
+    assert_eq!(
+        first_changed_block(old, "<h1>A</h1>\n<p>new</p>\n<p>one</p>\n<p>two</p>\n"),
+        Some(1)
+    );
+    assert_eq!(
+        first_changed_block(old, "<h1>A</h1>\n<p>one</p>\n"),
+        Some(1),
+        "Removing the last block points at the new last block"
+    );
+    assert_eq!(first_changed_block(old, ""), None);
+}
+
+#[tokio::test]
+async fn test_update_points_at_first_changed_block() {
+    let original = "# Plan\n\n## One\n\nFirst\n\n## Two\n\nSecond\n";
+    let (server, temp_file) = create_test_server_with_http(original).await;
+    let page = temp_file
+        .path()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .expect("temp file name")
+        .to_string();
+
+    let mut websocket = server
+        .get_websocket(&format!("/ws?file={page}"))
+        .await
+        .into_websocket()
+        .await;
+
+    // Write via rename so the watcher never sees a truncated file
+    let staging = temp_file.path().with_extension("md.tmp");
+    fs::write(
+        &staging,
+        "# Plan\n\n## One\n\nFirst\n\n## Two\n\nSecond, rewritten\n",
+    )
+    .expect("Failed to write staging file");
+    fs::rename(&staging, &temp_file).expect("Failed to move file into place");
+
+    let (_, changed_block) = receive_update_containing(&mut websocket, "rewritten").await;
+    assert_eq!(changed_block, Some(4));
+}
+
+#[test]
+fn test_annotate_changes_marks_inserted_deleted_and_modified_blocks() {
+    let old = "<h1>Plan</h1>\n<p>reword me</p>\n<p>keep</p>\n<p>drop me</p>\n";
+    let new = "<h1>Plan</h1>\n<p>reworded</p>\n<p>keep</p>\n<ul>\n<li>added</li>\n</ul>\n<ol>\n<li>also added</li>\n</ol>\n";
+
+    let annotated = annotate_changes(old, new);
+
+    assert_eq!(
+        annotated,
+        "<h1>Plan</h1>\n\
+         <div class=\"diff-block diff-modified\" title=\"modified\">\n<p>reworded</p>\n</div>\n\
+         <p>keep</p>\n\
+         <div class=\"diff-block diff-modified\" title=\"modified\">\n<ul>\n<li>added</li>\n</ul>\n</div>\n\
+         <div class=\"diff-block diff-inserted\" title=\"inserted\">\n<ol>\n<li>also added</li>\n</ol>\n</div>\n"
+    );
+
+    let removed = annotate_changes(old, "<h1>Plan</h1>\n<p>reword me</p>\n<p>keep</p>\n");
+    assert!(removed.ends_with(
+        "<div class=\"diff-block diff-deleted\" title=\"deleted\">\n<p>drop me</p>\n</div>\n"
+    ));
+
+    assert_eq!(annotate_changes(old, old).matches("diff-block").count(), 0);
+    assert_eq!(
+        annotate_changes("", new).matches("diff-inserted").count(),
+        5
+    );
+}
+
+#[tokio::test]
+async fn test_show_changes_annotates_updates() {
+    let (server, temp_dir) = create_directory_server_with_http().await;
+
+    let mut websocket = server
+        .get_websocket("/ws?file=test1.md")
+        .await
+        .into_websocket()
+        .await;
+
+    websocket
+        .send_json(&serde_json::json!({ "type": "ShowChanges", "since": "session" }))
+        .await;
+
+    let initial = tokio::time::timeout(
+        Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+        websocket.receive_json::<ServerMessage>(),
+    )
+    .await
+    .expect("Timeout waiting for annotated content");
+    match initial {
+        ServerMessage::Update { html, .. } => assert!(!html.contains("diff-block")),
+        other => panic!("Expected Update, got {other:?}"),
+    }
+
+    fs::write(
+        temp_dir.path().join("test1.md"),
+        "# Test 1\n\nContent of test1\n\nAppended paragraph",
+    )
+    .expect("Failed to modify file");
+
+    let (html, _) = receive_update_containing(&mut websocket, "Appended paragraph").await;
+    assert!(html.contains("diff-inserted"));
+    assert_eq!(html.matches("diff-block").count(), 1);
+
+    websocket
+        .send_json(&serde_json::json!({ "type": "ShowChanges", "since": "off" }))
+        .await;
+
+    // A single write can produce several watcher events, so annotated
+    // updates may still be queued ahead of the plain one
+    let plain = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        loop {
+            if let ServerMessage::Update { html, .. } = websocket.receive_json().await {
+                if !html.contains("diff-block") {
+                    return html;
+                }
+            }
+        }
+    })
+    .await
+    .expect("Timeout waiting for plain content");
+    assert!(plain.contains("Appended paragraph"));
+}
+
+#[test]
+fn test_highlight_code_blocks() {
+    let html = MarkdownState::markdown_to_html(
+        "```rust,ignore\nlet x = 1 < 2;\n```\n\n```mermaid\ngraph TD\n A-->B\n```\n\n```nosuchlang\na < b\n```\n\n```\nplain\n```\n",
+    )
+    .expect("Failed to render")
+    .html;
+
+    assert!(html
+        .contains(r#"<pre><code class="language-rust,ignore"><span class="hl-source hl-rust">"#));
+    assert!(html.contains(r#"<span class="hl-keyword hl-operator hl-rust">&lt;</span>"#));
+    assert!(
+        html.contains("<pre><code class=\"language-mermaid\">graph TD\n A--&gt;B\n</code></pre>")
+    );
+    assert!(html.contains("<pre><code class=\"language-nosuchlang\">a &lt; b\n</code></pre>"));
+    assert!(html.contains("<pre><code>plain\n</code></pre>"));
+}
+
+#[test]
+fn test_highlight_code_blocks_accepts_aliases() {
+    for language in ["ts", "TypeScript", "shell", "py", "bash"] {
+        let html = MarkdownState::markdown_to_html(&format!("```{language}\nx\n```\n"))
+            .expect("Failed to render")
+            .html;
+        assert!(
+            html.contains("hl-source"),
+            "{language} should be highlighted"
+        );
+    }
+}
+
+#[test]
+fn test_headings_get_slug_ids_and_are_collected() {
+    let RenderedMarkdown { html, headings, .. } = MarkdownState::markdown_to_html(
+        "# Rollout Plan\n\n## Step 1: *Build* & ship\n\n## Step 1: Build & ship\n\n### `snake_case` API\n",
+    )
+    .expect("Failed to render");
+
+    assert!(html.contains("<h1 id=\"rollout-plan\">Rollout Plan</h1>"));
+    assert!(html.contains("<h2 id=\"step-1-build--ship\">Step 1: <em>Build</em> &amp; ship</h2>"));
+    assert!(html.contains("<h2 id=\"step-1-build--ship-1\">"));
+    assert!(html.contains("<h3 id=\"snake_case-api\"><code>snake_case</code> API</h3>"));
+
+    let outline: Vec<(u8, &str, &str)> = headings
+        .iter()
+        .map(|heading| (heading.level, heading.text.as_str(), heading.id.as_str()))
+        .collect();
+    assert_eq!(
+        outline,
+        vec![
+            (1, "Rollout Plan", "rollout-plan"),
+            (2, "Step 1: Build & ship", "step-1-build--ship"),
+            (2, "Step 1: Build & ship", "step-1-build--ship-1"),
+            (3, "snake_case API", "snake_case-api"),
+        ]
+    );
+}
+
+#[test]
+fn test_slugs_match_github() {
+    let mut slugger = Slugger::default();
+    let slugs: Vec<String> = [
+        "What's new in v2.0?",
+        "🚀 Launch",
+        "Über uns",
+        "C++ & Rust",
+        "a-1",
+        "a",
+        "a",
+    ]
+    .iter()
+    .map(|text| slugger.slug(text))
+    .collect();
+
+    assert_eq!(
+        slugs,
+        vec![
+            "whats-new-in-v20",
+            "-launch",
+            "über-uns",
+            "c--rust",
+            "a-1",
+            "a",
+            "a-2"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_cross_document_fragment_links_in_directory_mode() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    fs::create_dir(temp_dir.path().join("guides")).expect("Failed to create guides dir");
+    fs::write(
+        temp_dir.path().join("index.md"),
+        "# Index\n\nSee the [rollout](guides/design.md#rollout-plan).\n",
+    )
+    .expect("Failed to write index.md");
+    fs::write(
+        temp_dir.path().join("guides/design.md"),
+        "# Design\n\n## Rollout Plan\n\nBack to [the index](../index.md#index).\n",
+    )
+    .expect("Failed to write design.md");
+
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::new(router).expect("Failed to create test server");
+
+    let index = server.get("/index.md").await.text();
+    assert!(index.contains(r#"<a href="/guides/design.md#rollout-plan">rollout</a>"#));
+    assert!(index.contains(r#"<h1 id="index">Index</h1>"#));
+
+    let design = server.get("/guides/design.md").await;
+    assert_eq!(design.status_code(), 200);
+    assert!(design
+        .text()
+        .contains(r#"<h2 id="rollout-plan">Rollout Plan</h2>"#));
+    assert!(design
+        .text()
+        .contains(r#"<a href="/index.md#index">the index</a>"#));
+}
+
+#[tokio::test]
+async fn test_page_and_updates_carry_table_of_contents() {
+    let (server, temp_file) = create_test_server_with_http("# Intro\n\n## Details\n").await;
+
+    let body = server.get("/").await.text();
+    assert!(body.contains("id=\"tocList\""));
+    assert!(body.contains(r#"renderToc([{"id":"intro","level":1,"text":"Intro"},{"id":"details","level":2,"text":"Details"}]);"#));
+
+    let page = temp_file
+        .path()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .expect("temp file name")
+        .to_string();
+    let mut websocket = server
+        .get_websocket(&format!("/ws?file={page}"))
+        .await
+        .into_websocket()
+        .await;
+    fs::write(&temp_file, "# Intro\n\n## Details\n\n## Added Section\n")
+        .expect("Failed to modify file");
+
+    let headings = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        loop {
+            if let ServerMessage::Update { headings, .. } = websocket.receive_json().await {
+                if headings.len() == 3 {
+                    return headings;
+                }
+            }
+        }
+    })
+    .await
+    .expect("Timeout waiting for update with new heading");
+    assert_eq!(headings[2].text, "Added Section");
+    assert_eq!(headings[2].id, "added-section");
+}
+
+#[tokio::test]
+async fn test_math_is_rendered_to_mathml() {
+    let markdown_content = r#"# Math
+
+Inline $E = mc^2$ and $a < b$ in a sentence, but not in `$code$`.
+
+$$
+\sum_{i=1}^{n} i = \frac{n(n+1)}{2}
+$$
+
+```math
+x^2
+```
+"#;
+
+    let (server, _temp_file) = create_test_server(markdown_content).await;
+
+    let response = server.get("/").await;
+    assert_eq!(response.status_code(), 200);
+    let body = response.text();
+
+    assert!(body.contains(r#"<math display="inline">"#));
+    assert_eq!(body.matches(r#"<math display="block">"#).count(), 2);
+    assert!(body.contains("<mfrac>"));
+    assert!(body.contains(r#"<annotation encoding="application/x-tex">E = mc^2</annotation>"#));
+    assert!(
+        body.contains("<mo>&lt;</mo>"),
+        "Operators must not produce raw tags"
+    );
+    assert!(body.contains("<code>$code$</code>"));
+    assert!(!body.contains("language-math"));
+}
+
+#[test]
+fn test_escape_stray_lt() {
+    assert_eq!(
+        escape_stray_lt("<mo><</mo><mi>a</mi>"),
+        "<mo>&lt;</mo><mi>a</mi>"
+    );
+    assert_eq!(escape_stray_lt("<mo>< </mo>"), "<mo>&lt; </mo>");
+}
+
+#[test]
+fn test_sections_split_at_headings() {
+    let sections = MarkdownState::markdown_to_html(
+        "Preamble *text*.\n\n# Intro\n\nFirst &amp; foremost.\n\n## Details\n\n- one\n- two\n",
+    )
+    .expect("Failed to render")
+    .sections;
+
+    let outline: Vec<(Option<&str>, &str)> = sections
+        .iter()
+        .map(|section| {
+            (
+                section.heading.as_ref().map(|heading| heading.id.as_str()),
+                section.text.as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        outline,
+        vec![
+            (None, "Preamble text."),
+            (Some("intro"), "First & foremost."),
+            (Some("details"), "one two"),
+        ]
+    );
+}
+
+#[test]
+fn test_snippet_is_cut_on_word_boundaries() {
+    let text = format!("{} needle {}", "lead ".repeat(40), "tail ".repeat(60));
+    let cut = snippet(&text, &["needle".to_string()]);
+
+    assert!(cut.starts_with("…lead "));
+    assert!(cut.ends_with("tail…"));
+    assert!(cut.contains("needle"));
+    assert!(cut.len() < text.len());
+
+    assert_eq!(snippet("short text", &["text".to_string()]), "short text");
+}
+
+#[tokio::test]
+async fn test_search_folds_non_ascii_case() {
+    let (server, _temp_file) = create_test_server("# Über\n\nДОБРО пожаловать\n").await;
+    let hits: Vec<SearchHit> = server
+        .get("/api/search")
+        .add_query_param("q", "üBER добро")
+        .await
+        .json();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].anchor.as_deref(), Some("über"));
+
+    let terms = ["ÉTÉ".to_lowercase(), "straße".to_string()];
+    assert_eq!(terms, ["été", "straße"]);
+
+    // "İ" lowercases to three bytes, shifting offsets in the folded text
+    let text = format!("İİİ {} Été à la STRASSE, straße", "mot ".repeat(30));
+    let cut = snippet(&text, &terms);
+    assert!(cut.starts_with("…mot "));
+    assert!(cut.contains("Été"));
+
+    assert_eq!(
+        first_match("ДОБРО пожаловать", &["пожаловать".to_string()]),
+        11
+    );
+}
+
+fn create_search_server() -> (TestServer, TempDir) {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    fs::create_dir(temp_dir.path().join("guides")).expect("Failed to create guides dir");
+    fs::write(
+        temp_dir.path().join("plan.md"),
+        "# Plan\n\nGeneral notes.\n\n## Rollout\n\nThe rollout happens in three waves. Each rollout wave is gated.\n",
+    )
+    .expect("Failed to write plan.md");
+    fs::write(
+        temp_dir.path().join("guides/ops.md"),
+        "---\ntitle: Operations\n---\n\n# Runbook\n\nPage the on-call before any rollout.\n",
+    )
+    .expect("Failed to write ops.md");
+    fs::write(temp_dir.path().join("other.md"), "# Other\n\nUnrelated.\n")
+        .expect("Failed to write other.md");
+
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::new(router).expect("Failed to create test server");
+    (server, temp_dir)
+}
+
+#[tokio::test]
+async fn test_search_returns_ranked_hits_with_anchors() {
+    let (server, _temp_dir) = create_search_server();
+
+    let hits: Vec<SearchHit> = server
+        .get("/api/search")
+        .add_query_param("q", "Rollout")
+        .await
+        .json();
+
+    let files: Vec<&str> = hits.iter().map(|hit| hit.file.as_str()).collect();
+    assert_eq!(files, vec!["plan.md", "guides/ops.md"]);
+    assert_eq!(hits[0].title, "plan.md");
+    assert_eq!(hits[0].heading.as_deref(), Some("Rollout"));
+    assert_eq!(hits[0].anchor.as_deref(), Some("rollout"));
+    assert!(hits[0].snippet.contains("three waves"));
+    assert_eq!(hits[1].title, "Operations");
+    assert_eq!(hits[1].anchor.as_deref(), Some("runbook"));
+
+    // Every term has to match somewhere in the file
+    let hits: Vec<SearchHit> = server
+        .get("/api/search")
+        .add_query_param("q", "rollout on-call")
+        .await
+        .json();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].file, "guides/ops.md");
+
+    // Titles count as matches
+    let hits: Vec<SearchHit> = server
+        .get("/api/search")
+        .add_query_param("q", "operations")
+        .await
+        .json();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].anchor, None);
+    assert!(hits[0].snippet.contains("on-call"));
+
+    let hits: Vec<SearchHit> = server.get("/api/search?q=").await.json();
+    assert!(hits.is_empty());
+}
+
+#[tokio::test]
+async fn test_search_index_follows_file_changes() {
+    let (server, temp_dir) = create_search_server();
+
+    fs::write(
+        temp_dir.path().join("other.md"),
+        "# Other\n\nNow mentions zeppelins.\n",
+    )
+    .expect("Failed to modify other.md");
+    fs::write(temp_dir.path().join("new.md"), "# New\n\nMore zeppelins.\n")
+        .expect("Failed to write new.md");
+
+    let files = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        loop {
+            let hits: Vec<SearchHit> = server
+                .get("/api/search")
+                .add_query_param("q", "zeppelins")
+                .await
+                .json();
+            let mut files: Vec<String> = hits.into_iter().map(|hit| hit.file).collect();
+            files.sort();
+            if files.len() == 2 {
+                return files;
+            }
+            tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+        }
+    })
+    .await
+    .expect("Timeout waiting for the search index to pick up changes");
+
+    assert_eq!(files, vec!["new.md", "other.md"]);
+}
+
+#[tokio::test]
+async fn test_sidebar_has_search_box() {
+    let (server, _temp_dir) = create_directory_server().await;
+    let body = server.get("/").await.text();
+    assert!(body.contains(r#"id="searchInput""#));
+
+    let (server, _temp_file) = create_test_server("# Single").await;
+    let body = server.get("/").await.text();
+    assert!(!body.contains(r#"id="searchInput""#));
+}
+
+#[test]
+fn test_export_link_rewriting() {
+    assert_eq!(export_link("guide.md", ""), "guide.html");
+    assert_eq!(
+        export_link("../README.markdown#setup", "../"),
+        "../README.html#setup"
+    );
+    assert_eq!(
+        export_link("/guides/a.md?x=1", "../"),
+        "../guides/a.html?x=1"
+    );
+    assert_eq!(
+        export_link("/mermaid.min.js", "../../"),
+        "../../mermaid.min.js"
+    );
+    assert_eq!(export_link("/", "../"), "../index.html");
+    assert_eq!(export_link("images/a.png", "../"), "images/a.png");
+    assert_eq!(export_link("#section", "../"), "#section");
+    assert_eq!(
+        export_link("https://example.com/a.md", ""),
+        "https://example.com/a.md"
+    );
+    assert_eq!(
+        export_link("mailto:someone@example.com", ""),
+        "mailto:someone@example.com"
+    );
+}
+
+#[test]
+fn test_export_site_writes_static_pages() {
+    let source = tempdir().expect("Failed to create temp dir");
+    fs::create_dir_all(source.path().join("guides/img")).expect("Failed to create dirs");
+    fs::write(
+        source.path().join("README.md"),
+        "# Home\n\nRead the [setup guide](guides/setup.md#install).\n",
+    )
+    .expect("Failed to write README.md");
+    fs::write(
+        source.path().join("guides/setup.md"),
+        "# Setup\n\n## Install\n\n![diagram](img/arch.png)\n\nBack [home](../README.md).\n\n```mermaid\ngraph TD\n A-->B\n```\n",
+    )
+    .expect("Failed to write setup.md");
+    fs::write(source.path().join("guides/img/arch.png"), b"png bytes")
+        .expect("Failed to write image");
+    fs::write(source.path().join("unused.png"), b"unused").expect("Failed to write image");
+
+    let output = tempdir().expect("Failed to create output dir");
+    let tracked_files =
+        scan_markdown_files(source.path(), &FileFilter::default()).expect("Failed to scan");
+    let pages = export_site(
+        source.path().to_path_buf(),
+        tracked_files,
+        true,
+        output.path(),
+    )
+    .expect("Export failed");
+    assert_eq!(pages, 2);
+
+    let home = fs::read_to_string(output.path().join("README.html")).expect("README.html");
+    assert!(home.contains(r#"<a href="guides/setup.html#install">setup guide</a>"#));
+    assert!(home.contains(r#"href="guides/setup.html""#), "sidebar link");
+    assert!(!home.contains("setupLiveReload();"));
+    assert!(!home.contains("id=\"searchInput\""));
+    assert!(!home.contains("changesToggle\" onclick"));
+
+    let setup = fs::read_to_string(output.path().join("guides/setup.html")).expect("setup.html");
+    assert!(setup.contains(r#"<a href="../README.html">home</a>"#));
+    assert!(setup.contains(r#"href="../README.html""#), "sidebar link");
+    assert!(setup.contains(r#"<script src="../mermaid.min.js"></script>"#));
+    assert!(setup.contains(r#"<img src="img/arch.png""#));
+
+    assert_eq!(
+        fs::read(output.path().join("guides/img/arch.png")).expect("image copied"),
+        b"png bytes"
+    );
+    assert!(!output.path().join("unused.png").exists());
+    assert!(output.path().join("mermaid.min.js").exists());
+
+    let index = fs::read_to_string(output.path().join("index.html")).expect("index.html");
+    assert!(index.contains("url=README.html"));
+}
+
+#[test]
+fn test_escape_inline_script() {
+    assert_eq!(
+        escape_inline_script(r#"a = "</script>"; b = "</SCRIPT >"; c = "</ScRiPt""#),
+        r#"a = "<\/script>"; b = "<\/SCRIPT >"; c = "<\/ScRiPt""#
+    );
+    assert_eq!(
+        escape_inline_script("x < /re/ && y </div> </sc"),
+        "x < /re/ && y </div> </sc"
+    );
+}
+
+#[test]
+fn test_export_single_file_is_self_contained() {
+    let source = tempdir().expect("Failed to create temp dir");
+    fs::create_dir(source.path().join("img")).expect("Failed to create img dir");
+    fs::write(source.path().join("img/dot.png"), b"\x89PNG").expect("Failed to write image");
+    let file = source.path().join("plan.md");
+    fs::write(
+        &file,
+        "# Plan\n\n![dot](img/dot.png)\n\n![remote](https://example.com/a.png)\n\n```mermaid\ngraph TD\n A-->B\n```\n",
+    )
+    .expect("Failed to write plan.md");
+
+    let output = tempdir().expect("Failed to create output dir");
+    let output_file = output.path().join("plan.html");
+    export_single_file(source.path().to_path_buf(), file, &output_file).expect("Export failed");
+
+    let page = fs::read_to_string(&output_file).expect("plan.html");
+    assert!(page.contains(r#"<img src="data:image/png;base64,iVBORw==" alt="dot" />"#));
+    assert!(page.contains(r#"<img src="https://example.com/a.png""#));
+    assert!(!page.contains(r#"<script src="/mermaid.min.js">"#));
+    assert!(page.len() > MERMAID_JS.len(), "Mermaid should be inlined");
+    assert!(!page.contains("setupLiveReload();"));
+    assert_eq!(fs::read_dir(output.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn test_print_route_renders_without_chrome() {
+    let (server, _temp_dir) = create_search_server();
+
+    let response = server.get("/print/guides/ops.md").await;
+    assert_eq!(response.status_code(), 200);
+    let body = response.text();
+    assert!(body.contains("Page the on-call before any rollout."));
+    assert!(!body.contains(r#"class="sidebar""#));
+    assert!(!body.contains(r#"class="theme-toggle""#));
+    assert!(!body.contains(r#"id="themeModal""#));
+    assert!(!body.contains("setupLiveReload();"));
+    assert!(body.contains("@media print"));
+
+    // Cover page from the frontmatter, no table of contents unless asked for
+    assert!(body.contains(r#"<section class="print-cover">"#));
+    assert!(body.contains("<h1>Operations</h1>"));
+    assert!(!body.contains(r#"<nav class="print-toc""#));
+
+    let body = server.get("/print/guides/ops.md?toc=1").await.text();
+    assert!(body.contains(r#"<nav class="print-toc""#));
+    assert!(body.contains(r##"<a href="#runbook">Runbook</a>"##));
+
+    // Without frontmatter there is nothing to put on a cover page
+    let body = server.get("/print/plan.md?toc=true").await.text();
+    assert!(!body.contains(r#"<section class="print-cover">"#));
+    assert!(body.contains(r##"<a href="#rollout">Rollout</a>"##));
+
+    let response = server.get("/print/missing.md").expect_failure().await;
+    assert_eq!(response.status_code(), 404);
+
+    let body = server.get("/print/plan.md?toc=0").await.text();
+    assert!(!body.contains(r#"<nav class="print-toc""#));
+}
+
+#[tokio::test]
+async fn test_print_directory_is_not_shadowed() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    fs::create_dir(temp_dir.path().join("print")).expect("Failed to create print dir");
+    fs::write(temp_dir.path().join("notes.md"), "# Top level").expect("Failed to write file");
+    fs::write(temp_dir.path().join("print/notes.md"), "# In print").expect("Failed to write file");
+    fs::write(temp_dir.path().join("other.md"), "# Other").expect("Failed to write file");
+    fs::write(temp_dir.path().join("print/chart.png"), b"in print").expect("Failed to write file");
+    fs::write(temp_dir.path().join("logo.png"), b"top level").expect("Failed to write file");
+
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::new(router).expect("Failed to create test server");
+
+    // A tracked `print/` file wins over the print layout of `notes.md`
+    let body = server.get("/print/notes.md").await.text();
+    assert!(body.contains("In print"));
+    assert!(body.contains("setupLiveReload();"));
+    let body = server.get("/print/print/notes.md").await.text();
+    assert!(body.contains("In print"));
+    assert!(!body.contains("setupLiveReload();"));
+    let body = server.get("/print/other.md").await.text();
+    assert!(body.contains("Other"));
+    assert!(!body.contains("setupLiveReload();"));
+
+    // Images of a print page resolve under `/print/` too
+    assert_eq!(server.get("/print/chart.png").await.text(), "in print");
+    assert_eq!(server.get("/print/logo.png").await.text(), "top level");
+}
+
+#[test]
+fn test_markdown_links_resolve_to_route_keys() {
+    let tracked = ["index.md", "api/auth.md", "guides/intro.md", "my notes.md"];
+    let resolve =
+        |page: &str, html: &str| resolve_markdown_links(page, html, |key| tracked.contains(&key));
+
+    assert_eq!(
+        resolve(
+            "guides/intro.md",
+            r#"<a href="../api/auth.md#tokens">auth</a>"#
+        ),
+        r#"<a href="/api/auth.md#tokens">auth</a>"#
+    );
+    assert_eq!(
+        resolve("guides/intro.md", r#"<a href="/index.md">home</a>"#),
+        r#"<a href="/index.md">home</a>"#
+    );
+    assert_eq!(
+        resolve("index.md", r#"<a href="my%20notes.md">notes</a>"#),
+        r#"<a href="/my%20notes.md">notes</a>"#
+    );
+
+    // Other links are left alone
+    for html in [
+        r#"<a href="https://example.com/a.md">remote</a>"#,
+        r##"<a href="#intro">here</a>"##,
+        r#"<a href="diagram.png">diagram</a>"#,
+    ] {
+        assert_eq!(resolve("guides/intro.md", html), html);
+    }
+
+    assert_eq!(
+        resolve(
+            "guides/intro.md",
+            r#"<a href="missing.md" title="Later">soon</a>"#
+        ),
+        r#"<a href="/guides/missing.md" class="broken-link" title="Broken link: guides/missing.md is not a tracked file" title="Later">soon</a>"#
+    );
+}
+
+#[tokio::test]
+async fn test_broken_links_are_marked_until_target_exists() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    fs::create_dir(temp_dir.path().join("guides")).expect("Failed to create guides dir");
+    fs::create_dir(temp_dir.path().join("api")).expect("Failed to create api dir");
+    fs::write(
+        temp_dir.path().join("guides/intro.md"),
+        "# Intro\n\nSee [auth](../api/auth.md).\n",
+    )
+    .expect("Failed to write intro.md");
+
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::new(router).expect("Failed to create test server");
+
+    let body = server.get("/guides/intro.md").await.text();
+    assert!(body.contains(r#"<a href="/api/auth.md" class="broken-link" title="Broken link: api/auth.md is not a tracked file">auth</a>"#));
+
+    fs::write(temp_dir.path().join("api/auth.md"), "# Auth\n").expect("Failed to write auth.md");
+
+    let resolved = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+        loop {
+            let body = server.get("/guides/intro.md").await.text();
+            if body.contains(r#"<a href="/api/auth.md">auth</a>"#) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(resolved.is_ok(), "link should resolve once the file exists");
+}
+
+#[tokio::test]
+async fn test_check_reports_broken_links_anchors_and_images() {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    fs::create_dir(temp_dir.path().join("guides")).expect("Failed to create guides dir");
+    fs::write(temp_dir.path().join("ok.png"), b"png").expect("Failed to write ok.png");
+    fs::write(
+        temp_dir.path().join("index.md"),
+        "# Index\n\n[design](guides/design.md#rollout-plan) [gone](gone.md)\n\n![ok](ok.png)\n![missing](missing.png)\n\n[here](#index) [there](#nowhere) [web](https://example.com/a.md)\n",
+    )
+    .expect("Failed to write index.md");
+    fs::write(
+        temp_dir.path().join("guides/design.md"),
+        "# Design\n\n## Rollout Plan\n\n[home](../index.md#intro)\n\n[ref]: ../index.md\n",
+    )
+    .expect("Failed to write design.md");
+
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
+    let router = new_router(base_dir, tracked_files, true, &ServeOptions::default())
+        .expect("Failed to create router");
+    let server = TestServer::new(router).expect("Failed to create test server");
+
+    let issues: Vec<CheckIssue> = server.get("/api/check").await.json();
+    let summary: Vec<(&str, usize, CheckIssueKind, &str)> = issues
+        .iter()
+        .map(|issue| {
+            (
+                issue.file.as_str(),
+                issue.line,
+                issue.kind,
+                issue.target.as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (
+                "guides/design.md",
+                5,
+                CheckIssueKind::MissingAnchor,
+                "../index.md#intro"
+            ),
+            ("index.md", 3, CheckIssueKind::BrokenLink, "gone.md"),
+            ("index.md", 6, CheckIssueKind::MissingImage, "missing.png"),
+            ("index.md", 8, CheckIssueKind::MissingAnchor, "#nowhere"),
+        ]
+    );
+    assert_eq!(issues[1].column, 41);
+    assert_eq!(
+        issues[1].to_string(),
+        "index.md:3:41: link to gone.md is not a tracked file"
+    );
+}
+
+#[tokio::test]
+async fn test_token_auth_guards_every_route() {
+    let (server, _temp_dir) = create_directory_server_with_options(ServeOptions {
+        auth: Some(Auth::Token("s3cret".to_string())),
+        ..ServeOptions::default()
+    })
+    .await;
+
+    for path in [
+        "/",
+        "/test1.md",
+        "/ws",
+        "/mermaid.min.js",
+        "/api/search?q=x",
+        "/?token=wrong",
+    ] {
+        let response = server.get(path).expect_failure().await;
+        assert_eq!(response.status_code(), 401, "{path}");
+        assert!(!response.text().contains("Content of test1"));
+    }
+
+    // The token in the URL sets the cookie and is dropped from the address
+    let response = server.get("/test1.md?toc=1&token=s3cret").await;
+    assert_eq!(response.status_code(), 303);
+    assert_eq!(response.header("location"), "/test1.md?toc=1");
+    let cookie = response.header("set-cookie");
+    let cookie = cookie.to_str().expect("cookie is ASCII");
+    assert!(cookie.starts_with("mdserve_token=s3cret;"));
+    assert!(cookie.contains("HttpOnly"));
+    assert!(cookie.contains("SameSite=Lax"));
+    assert!(!cookie.contains("Secure"));
+
+    let response = server
+        .get("/test1.md")
+        .add_header(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; mdserve_token=s3cret"),
+        )
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert!(response.text().contains("Content of test1"));
+
+    let websocket = server
+        .get_websocket("/ws?file=test1.md")
+        .add_header(
+            header::COOKIE,
+            HeaderValue::from_static("mdserve_token=s3cret"),
+        )
+        .await;
+    assert_eq!(websocket.status_code(), 101);
+    let websocket = server.get_websocket("/ws?token=s3cret").await;
+    assert_eq!(websocket.status_code(), 101);
+}
+
+#[tokio::test]
+async fn test_token_cookie_is_secure_over_https() {
+    let (server, _temp_dir) = create_directory_server_with_options(ServeOptions {
+        auth: Some(Auth::Token("s3cret".to_string())),
+        tls: Some(Tls::SelfSigned),
+        ..ServeOptions::default()
+    })
+    .await;
+
+    let response = server.get("/?token=s3cret").await;
+    let cookie = response.header("set-cookie");
+    let cookie = cookie.to_str().expect("cookie is ASCII");
+    assert!(cookie.contains("SameSite=Lax"));
+    assert!(cookie.ends_with("; Secure"));
+}
+
+#[tokio::test]
+async fn test_basic_auth_guards_every_route() {
+    let (server, _temp_dir) = create_directory_server_with_options(ServeOptions {
+        auth: Some(Auth::Basic("ada:s3cret".to_string())),
+        ..ServeOptions::default()
+    })
+    .await;
+
+    let response = server.get("/test1.md").expect_failure().await;
+    assert_eq!(response.status_code(), 401);
+    assert_eq!(
+        response.header("www-authenticate"),
+        r#"Basic realm="mdserve""#
+    );
+
+    let wrong = format!("Basic {}", BASE64_STANDARD.encode("ada:guess"));
+    let response = server
+        .get("/test1.md")
+        .add_header(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&wrong).unwrap(),
+        )
+        .expect_failure()
+        .await;
+    assert_eq!(response.status_code(), 401);
+
+    let right =
+        HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode("ada:s3cret"))).unwrap();
+    let response = server
+        .get("/test1.md")
+        .add_header(header::AUTHORIZATION, right.clone())
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert!(response.text().contains("Content of test1"));
+
+    let websocket = server.get_websocket("/ws").expect_failure().await;
+    assert_eq!(websocket.status_code(), 401);
+    let websocket = server
+        .get_websocket("/ws")
+        .add_header(header::AUTHORIZATION, right)
+        .await;
+    assert_eq!(websocket.status_code(), 101);
+}
+
+#[tokio::test]
+async fn test_unknown_hosts_are_refused() {
+    let (server, _temp_dir) = create_directory_server_with_http().await;
+
+    let response = server
+        .get("/test1.md")
+        .add_header(header::HOST, HeaderValue::from_static("evil.example:3000"))
+        .expect_failure()
+        .await;
+    assert_eq!(response.status_code(), 403);
+
+    for host in ["localhost:3000", "127.0.0.1:3000"] {
+        let response = server
+            .get("/test1.md")
+            .add_header(header::HOST, HeaderValue::from_static(host))
+            .await;
+        assert_eq!(response.status_code(), 200);
+    }
+}
+
+#[tokio::test]
+async fn test_cors_is_same_origin_unless_configured() {
+    let origin = HeaderValue::from_static("https://docs.example");
+
+    let (server, _temp_dir) = create_directory_server_with_http().await;
+    let response = server
+        .get("/test1.md")
+        .add_header(header::ORIGIN, origin.clone())
+        .await;
+    assert!(response
+        .maybe_header(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        .is_none());
+
+    let (server, _temp_dir) = create_directory_server_with_options(ServeOptions {
+        cors_origins: vec!["https://docs.example".to_string()],
+        ..ServeOptions::default()
+    })
+    .await;
+    let response = server
+        .get("/test1.md")
+        .add_header(header::ORIGIN, origin.clone())
+        .await;
+    assert_eq!(response.header(header::ACCESS_CONTROL_ALLOW_ORIGIN), origin);
+    let response = server
+        .get("/test1.md")
+        .add_header(
+            header::ORIGIN,
+            HeaderValue::from_static("https://evil.example"),
+        )
+        .await;
+    assert!(response
+        .maybe_header(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_cross_origin_websockets_are_refused() {
+    let (server, _temp_dir) = create_directory_server_with_http().await;
+    let address = server
+        .server_address()
+        .expect("http transport has an address");
+    let own_origin = format!(
+        "http://{}:{}",
+        address.host_str().unwrap(),
+        address.port().unwrap()
+    );
+
+    let websocket = server
+        .get_websocket("/ws")
+        .add_header(
+            header::ORIGIN,
+            HeaderValue::from_static("http://evil.example"),
+        )
+        .expect_failure()
+        .await;
+    assert_eq!(websocket.status_code(), 403);
+
+    let websocket = server
+        .get_websocket("/ws")
+        .add_header(header::ORIGIN, HeaderValue::from_str(&own_origin).unwrap())
+        .await;
+    assert_eq!(websocket.status_code(), 101);
+}
+
+#[test]
+fn test_socket_origins_accept_configured_names() {
+    let origins = SocketOrigins {
+        allowed_hosts: AllowedHosts::new(vec!["docs.example.com".to_string()]),
+        cors_origins: vec!["https://app.example".to_string()],
+    };
+    let request = |uri: &str, host: Option<&str>, origin: &str| {
+        let mut builder = Request::builder().uri(uri).header(header::ORIGIN, origin);
+        if let Some(host) = host {
+            builder = builder.header(header::HOST, host);
+        }
+        builder.body(axum::body::Body::empty()).unwrap()
+    };
+
+    for (host, origin, allowed) in [
+        (Some("127.0.0.1:3000"), "http://127.0.0.1:3000", true),
+        // Behind a proxy that rewrites `Host`
+        (Some("127.0.0.1:3000"), "https://docs.example.com", true),
+        (Some("127.0.0.1:3000"), "https://app.example", true),
+        (Some("127.0.0.1:3000"), "http://evil.example", false),
+        // Any address passes the host check, but not as an origin
+        (Some("127.0.0.1:3000"), "http://203.0.113.5", false),
+        // HTTP/2 has no `Host` header
+        (None, "https://127.0.0.1:3000", true),
+        (None, "https://127.0.0.1:4000", false),
+    ] {
+        let request = request("https://127.0.0.1:3000/ws", host, origin);
+        assert_eq!(origins.allows(&request), allowed, "{origin} to {host:?}");
+    }
+}
+
+#[test]
+fn test_self_signed_names() {
+    let none = AllowedHosts::new(Vec::new());
+    assert_eq!(
+        self_signed_names("127.0.0.1", &none, &[]),
+        ["localhost", "127.0.0.1", "::1"]
+    );
+    assert_eq!(
+        self_signed_names("0.0.0.0", &none, &[]),
+        ["localhost", "127.0.0.1", "::1"]
+    );
+    assert_eq!(
+        self_signed_names("DevBox.lan", &none, &[]),
+        ["localhost", "127.0.0.1", "::1", "devbox.lan"]
+    );
+
+    let allowed = AllowedHosts::new(vec![
+        "docs.example".to_string(),
+        "DevBox.lan".to_string(),
+        "10.8.0.2".to_string(),
+        "*".to_string(),
+    ]);
+    let interfaces = ["192.168.1.20".parse().unwrap(), "fd00::20".parse().unwrap()];
+    assert_eq!(
+        self_signed_names("0.0.0.0", &allowed, &interfaces),
+        [
+            "localhost",
+            "127.0.0.1",
+            "::1",
+            "docs.example",
+            "devbox.lan",
+            "10.8.0.2",
+            "192.168.1.20",
+            "fd00::20"
+        ]
+    );
+}
+
+#[test]
+fn test_interface_addresses_skip_loopback() {
+    assert!(interface_addresses()
+        .iter()
+        .all(|address| !address.is_loopback() && !address.is_unspecified()));
+}
+
+#[tokio::test]
+async fn test_tls_certificates_load() {
+    let allowed = AllowedHosts::default();
+    assert!(Tls::SelfSigned.config("devbox.lan", &allowed).await.is_ok());
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let cert = temp_dir.path().join("cert.pem");
+    let key = temp_dir.path().join("key.pem");
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    let files = Tls::Files {
+        cert: cert.clone(),
+        key: key.clone(),
+    };
+    assert!(files.config("localhost", &allowed).await.is_ok());
+
+    fs::remove_file(&key).unwrap();
+    let error = files.config("localhost", &allowed).await.unwrap_err();
+    assert!(error.to_string().contains("key.pem"));
+}
+
+#[tokio::test]
+async fn test_https_request_end_to_end() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    fs::write(temp_dir.path().join("test1.md"), TEST_FILE_1_CONTENT).expect("Failed to write");
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = temp_dir.path().join("cert.pem");
+    let key = temp_dir.path().join("key.pem");
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+    let options = ServeOptions {
+        tls: Some(Tls::Files { cert, key }),
+        ..ServeOptions::default()
+    };
+    let tls_config = options
+        .tls
+        .as_ref()
+        .unwrap()
+        .config("127.0.0.1", &options.allowed_hosts)
+        .await
+        .expect("Failed to load certificate");
+    let base_dir = temp_dir.path().to_path_buf();
+    let tracked_files = scan_markdown_files(&base_dir, &FileFilter::default())
+        .expect("Failed to scan markdown files");
+    let router =
+        new_router(base_dir, tracked_files, true, &options).expect("Failed to create router");
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind");
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve_router(listener, router, Some(tls_config)));
+
+    // Trust only the certificate the server was given
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let client = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+    let stream = tokio::net::TcpStream::connect(address)
+        .await
+        .expect("Failed to connect");
+    let server_name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    let mut stream = connector
+        .connect(server_name, stream)
+        .await
+        .expect("TLS handshake failed");
+
+    stream
+        .write_all(b"GET /test1.md HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .expect("Failed to send request");
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .await
+        .expect("Failed to read response");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.contains("Content of test1"));
+}
@@ -1,18 +1,35 @@
 use anyhow::Result;
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 mod app;
 
-use app::{scan_markdown_files, serve_markdown};
+#[cfg(test)]
+#[path = "main_tests.rs"]
+mod tests;
+
+use app::{
+    check_links, export_single_file, export_site, scan_markdown_files, serve_markdown,
+    AllowedHosts, Auth, FileFilter, ServeOptions, Tls, DEFAULT_DEBOUNCE, DEFAULT_RENDER_CACHE,
+};
 
 #[derive(Parser)]
 #[command(name = "mdserve")]
 #[command(about = "A simple HTTP server for markdown preview")]
 #[command(version)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Path to markdown file or directory to serve
-    path: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to markdown file or directory to serve. One named like a
+    /// subcommand needs a `./` prefix or a preceding `--`
+    #[arg(required = true)]
+    path: Option<PathBuf>,
 
     /// Hostname (domain or IP address) to listen on
     #[arg(short = 'H', long, default_value = "127.0.0.1")]
@@ -25,30 +42,182 @@ struct Args {
     /// Don't open the preview in the default browser
     #[arg(long)]
     no_open: bool,
+
+    /// Milliseconds to wait for a burst of file changes to settle before
+    /// re-rendering (0 to disable)
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_DEBOUNCE.as_millis() as u64)]
+    debounce: u64,
+
+    /// Poll for changes every MS milliseconds (default 1000) instead of
+    /// relying on OS notifications, e.g. on network or container mounts
+    #[arg(
+        long,
+        value_name = "MS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1000",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    poll: Option<u64>,
+
+    /// Maximum number of rendered pages kept in memory; pages are rendered
+    /// on first request and the least recently used are dropped
+    #[arg(long, value_name = "PAGES", default_value_t = DEFAULT_RENDER_CACHE)]
+    render_cache: NonZeroUsize,
+
+    /// Render pages in the background at startup instead of on first request
+    #[arg(long)]
+    prewarm: bool,
+
+    /// Require HTTP basic authentication with these credentials
+    #[arg(long, value_name = "USER:PASSWORD", conflicts_with = "token")]
+    auth: Option<String>,
+
+    /// Require a random token, generated at startup and included in the
+    /// printed and opened URL
+    #[arg(long)]
+    token: bool,
+
+    /// Also answer requests addressed to HOST (repeatable); localhost, IP
+    /// addresses and the bind hostname always are. `*` accepts any host
+    #[arg(long = "allowed-host", value_name = "HOST")]
+    allowed_hosts: Vec<String>,
+
+    /// Let pages on ORIGIN (e.g. https://example.com) read responses
+    /// cross-origin (repeatable); by default only same-origin requests can
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    cors_origins: Vec<String>,
+
+    /// Serve HTTPS with this PEM certificate (chain)
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key for --tls-cert, in PEM
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS with a self-signed certificate generated at startup, for
+    /// the bind hostname, every --allowed-host and the machine's main
+    /// network addresses
+    #[arg(long, conflicts_with = "tls_cert")]
+    tls_self_signed: bool,
+
+    #[command(flatten)]
+    filter: FilterArgs,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-    let absolute_path = args.path.canonicalize().unwrap_or(args.path);
+/// Which files directory mode picks up.
+#[derive(clap::Args)]
+struct FilterArgs {
+    /// Skip files and directories matching GLOB (gitignore syntax, relative
+    /// to the directory; repeatable)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Only track markdown files matching GLOB (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Don't honour .gitignore and .ignore files
+    #[arg(long)]
+    no_ignore: bool,
+}
+
+impl FilterArgs {
+    fn into_filter(self) -> Result<FileFilter> {
+        FileFilter::new(self.include, self.exclude, !self.no_ignore)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write the rendered markdown to a static HTML site instead of serving it
+    Export {
+        /// Path to markdown file or directory to export
+        path: PathBuf,
+
+        /// Where to write the export [default: site, or <name>.html with --single-file]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Write one self-contained HTML file (images and scripts inlined)
+        /// instead of a site; PATH must be a markdown file
+        #[arg(long)]
+        single_file: bool,
 
-    let (base_dir, tracked_files, is_directory_mode) = if absolute_path.is_file() {
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Report broken links, missing anchors and missing images; exits with
+    /// status 1 when there are any
+    Check {
+        /// Path to markdown file or directory to check
+        path: PathBuf,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+/// Resolves the base directory, files to track and mode for a path argument.
+fn resolve_path(path: &Path, filter: &FileFilter) -> Result<(PathBuf, Vec<PathBuf>, bool)> {
+    let absolute_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    if absolute_path.is_file() {
         // Single-file mode: derive parent directory
         let base_dir = absolute_path
             .parent()
             .unwrap_or_else(|| std::path::Path::new("."))
             .to_path_buf();
         let tracked_files = vec![absolute_path];
-        (base_dir, tracked_files, false)
+        Ok((base_dir, tracked_files, false))
     } else if absolute_path.is_dir() {
         // Directory mode: scan directory for markdown files
-        let tracked_files = scan_markdown_files(&absolute_path)?;
+        let tracked_files = scan_markdown_files(&absolute_path, filter)?;
         if tracked_files.is_empty() {
             anyhow::bail!("No markdown files found in directory");
         }
-        (absolute_path, tracked_files, true)
+        Ok((absolute_path, tracked_files, true))
     } else {
         anyhow::bail!("Path must be a file or directory");
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    match args.command {
+        Some(Command::Export {
+            path,
+            output,
+            single_file,
+            filter,
+        }) => return export(&path, output, single_file, &filter.into_filter()?),
+        Some(Command::Check { path, json, filter }) => {
+            return check(&path, json, &filter.into_filter()?)
+        }
+        None => {}
+    }
+
+    let path = args
+        .path
+        .expect("clap requires a path without a subcommand");
+    let filter = args.filter.into_filter()?;
+    let (base_dir, tracked_files, is_directory_mode) = resolve_path(&path, &filter)?;
+    let auth = match args.auth {
+        Some(credentials) => Some(Auth::basic(&credentials)?),
+        None if args.token => Some(Auth::random_token()?),
+        None => None,
+    };
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => Some(Tls::Files { cert, key }),
+        _ if args.tls_self_signed => Some(Tls::SelfSigned),
+        _ => None,
     };
 
     // Single unified serve function
@@ -59,8 +228,69 @@ async fn main() -> Result<()> {
         args.hostname,
         args.port,
         !args.no_open,
+        ServeOptions {
+            filter,
+            debounce: Duration::from_millis(args.debounce),
+            poll: args.poll.map(Duration::from_millis),
+            render_cache: args.render_cache,
+            prewarm: args.prewarm,
+            auth,
+            allowed_hosts: AllowedHosts::new(args.allowed_hosts),
+            cors_origins: args.cors_origins,
+            tls,
+        },
     )
     .await?;
 
     Ok(())
 }
+
+/// Runs the `export` subcommand.
+fn export(
+    path: &Path,
+    output: Option<PathBuf>,
+    single_file: bool,
+    filter: &FileFilter,
+) -> Result<()> {
+    let (base_dir, mut tracked_files, is_directory_mode) = resolve_path(path, filter)?;
+
+    if single_file {
+        if is_directory_mode {
+            anyhow::bail!("--single-file exports a markdown file, not a directory");
+        }
+        let file = tracked_files.remove(0);
+        let output = output.unwrap_or_else(|| {
+            PathBuf::from(file.file_stem().unwrap_or_default()).with_extension("html")
+        });
+        export_single_file(base_dir, file, &output)?;
+        println!("Exported {}", output.display());
+    } else {
+        let output = output.unwrap_or_else(|| PathBuf::from("site"));
+        let pages = export_site(base_dir, tracked_files, is_directory_mode, &output)?;
+        println!("Exported {pages} page(s) to {}", output.display());
+    }
+    Ok(())
+}
+
+/// Runs the `check` subcommand, exiting with status 1 when it finds problems.
+fn check(path: &Path, json: bool, filter: &FileFilter) -> Result<()> {
+    let (base_dir, tracked_files, is_directory_mode) = resolve_path(path, filter)?;
+    let files = tracked_files.len();
+    let issues = check_links(base_dir, tracked_files, is_directory_mode)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&issues)?);
+    } else if issues.is_empty() {
+        println!("No problems found in {files} file(s)");
+    } else {
+        for issue in &issues {
+            println!("{issue}");
+        }
+        println!("{} problem(s) found in {files} file(s)", issues.len());
+    }
+
+    if !issues.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use super::*;
+
+fn serve_path(args: &[&str]) -> Option<PathBuf> {
+    let args = Args::try_parse_from(args).expect("Failed to parse arguments");
+    assert!(args.command.is_none());
+    args.path
+}
+
+#[test]
+fn test_subcommand_names_parse_as_subcommands() {
+    let args = Args::try_parse_from(["mdserve", "export", "docs"]).unwrap();
+    assert!(
+        matches!(args.command, Some(Command::Export { path, .. }) if path == Path::new("docs"))
+    );
+
+    let args = Args::try_parse_from(["mdserve", "check", "docs"]).unwrap();
+    assert!(matches!(args.command, Some(Command::Check { .. })));
+}
+
+#[test]
+fn test_directories_named_like_subcommands_can_be_served() {
+    assert_eq!(
+        serve_path(&["mdserve", "./export"]),
+        Some(PathBuf::from("./export"))
+    );
+    assert_eq!(
+        serve_path(&["mdserve", "--", "export"]),
+        Some(PathBuf::from("export"))
+    );
+    assert_eq!(
+        serve_path(&["mdserve", "--port", "8080", "--", "check"]),
+        Some(PathBuf::from("check"))
+    );
+    assert_eq!(
+        serve_path(&["mdserve", "docs"]),
+        Some(PathBuf::from("docs"))
+    );
+}
+
+#[test]
+fn test_poll_interval_must_be_positive() {
+    let poll = |args: &[&str]| Args::try_parse_from(args).map(|args| args.poll);
+
+    assert_eq!(poll(&["mdserve", "docs"]).unwrap(), None);
+    assert_eq!(poll(&["mdserve", "--poll", "docs"]).unwrap(), Some(1000));
+    assert_eq!(poll(&["mdserve", "--poll=250", "docs"]).unwrap(), Some(250));
+    assert!(poll(&["mdserve", "--poll=0", "docs"]).is_err());
+}